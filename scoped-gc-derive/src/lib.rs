@@ -6,6 +6,8 @@ extern crate syn;
 extern crate synstructure;
 
 decl_derive!([Trace] => derive_trace);
+decl_derive!([Finalize] => derive_finalize);
+decl_derive!([HeapSize] => derive_heap_size);
 
 fn derive_trace(s: synstructure::Structure) -> quote::Tokens {
   let trace_body = s.each(|bi| quote!(mark(#bi)));
@@ -19,23 +21,23 @@ fn derive_trace(s: synstructure::Structure) -> quote::Tokens {
       }
       match *self { #trace_body }
     }
-    #[inline] unsafe fn root(&self) {
-      #[allow(dead_code)]
-      #[inline]
-      unsafe fn mark<T: ::scoped_gc::Trace>(it: &T) {
-        ::scoped_gc::Trace::root(it);
-      }
-      match *self { #trace_body }
-    }
-    #[inline] unsafe fn unroot(&self) {
-      #[allow(dead_code)]
-      #[inline]
-      unsafe fn mark<T: ::scoped_gc::Trace>(it: &T) {
-        ::scoped_gc::Trace::unroot(it);
-      }
-      match *self { #trace_body }
-    }
   });
 
   quote! { #trace_impl }
 }
+
+fn derive_finalize(s: synstructure::Structure) -> quote::Tokens {
+  s.unbound_impl(quote!(::scoped_gc::Finalize), quote!())
+}
+
+fn derive_heap_size(s: synstructure::Structure) -> quote::Tokens {
+  let heap_size_body = s.each(|bi| quote!(size += ::scoped_gc::HeapSize::heap_size(#bi)));
+
+  s.bound_impl(quote!(::scoped_gc::HeapSize), quote! {
+    fn heap_size(&self) -> usize {
+      let mut size: usize = 0;
+      match *self { #heap_size_body }
+      size
+    }
+  })
+}
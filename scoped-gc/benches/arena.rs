@@ -0,0 +1,75 @@
+//! Compares the counted `Gc` pointers of `GcScope` with the uncounted `ArenaGc` pointers of
+//! `GcArena`, on the allocation of a linked list followed by a full collection.
+
+#![feature(test)]
+
+extern crate scoped_gc;
+extern crate test;
+
+use scoped_gc::{ArenaGc, Finalize, Gc, GcArena, GcConfig, GcRefCell, GcScope, HeapSize, NeverPolicy, Rootable, Trace};
+use test::Bencher;
+
+const LIST_LENGTH: usize = 10_000;
+
+struct ScopeNode<'gc> {
+  next: Option<Gc<'gc, ScopeNode<'gc>>>,
+}
+
+impl<'gc> Finalize for ScopeNode<'gc> {}
+
+impl<'gc> HeapSize for ScopeNode<'gc> {}
+
+unsafe impl<'gc> Trace for ScopeNode<'gc> {
+  unsafe fn mark(&self) {
+    self.next.mark();
+  }
+}
+
+struct ArenaNode<'gc> {
+  next: Option<ArenaGc<'gc, ArenaNode<'gc>>>,
+}
+
+impl<'gc> Finalize for ArenaNode<'gc> {}
+
+impl<'gc> HeapSize for ArenaNode<'gc> {}
+
+unsafe impl<'gc> Trace for ArenaNode<'gc> {
+  unsafe fn mark(&self) {
+    self.next.mark();
+  }
+}
+
+struct ListRoot;
+
+impl<'gc> Rootable<'gc> for ListRoot {
+  type Root = GcRefCell<Option<ArenaGc<'gc, ArenaNode<'gc>>>>;
+}
+
+#[bench]
+fn scope_list(b: &mut Bencher) {
+  b.iter(|| {
+    let scope: GcScope = GcScope::with_config(GcConfig::manual());
+    let mut head: Option<Gc<ScopeNode>> = None;
+    for _ in 0..LIST_LENGTH {
+      head = Some(scope.alloc(ScopeNode { next: head }).unwrap());
+    }
+    scope.collect_garbage();
+    test::black_box(head.is_some());
+  });
+}
+
+#[bench]
+fn arena_list(b: &mut Bencher) {
+  b.iter(|| {
+    let mut arena: GcArena<ListRoot> = GcArena::with_policy(NeverPolicy, |_| GcRefCell::new(None));
+    arena.mutate(|mc, root| {
+      let mut head: Option<ArenaGc<ArenaNode>> = None;
+      for _ in 0..LIST_LENGTH {
+        head = Some(mc.alloc(ArenaNode { next: head }).unwrap());
+      }
+      *root.borrow_mut() = head;
+    });
+    arena.collect_garbage();
+    test::black_box(arena.mutate(|_, root| root.borrow().is_some()));
+  });
+}
@@ -0,0 +1,67 @@
+use ::std::cell::Cell;
+use ::std::marker::PhantomData;
+use ::std::ops::Deref;
+use ::std::ptr::NonNull;
+use finalize::Finalize;
+use gc_box::GcBox;
+use heap_size::HeapSize;
+use trace::Trace;
+
+/// A smart pointer to a value managed by a `GcArena`.
+///
+/// It is created by `MutationContext::alloc` and can only be used during the call to
+/// `GcArena::mutate` where it was created, or stored in the values reachable from the root of the
+/// arena.
+///
+/// Unlike `Gc`, this pointer is not counted: it is `Copy` and creating or dropping it does not
+/// touch its value. The arena only collects garbage between two mutations, when every reachable
+/// value is reachable from its root.
+#[derive(Debug)]
+pub struct ArenaGc<'gc, T: Trace + 'gc> {
+  ptr: NonNull<GcBox<'gc, T>>,
+  // `'gc` is invariant so pointers of different mutations can't be mixed
+  _invariant: PhantomData<Cell<&'gc ()>>,
+}
+
+impl<'gc, T: Trace + 'gc> ArenaGc<'gc, T> {
+  pub(crate) fn new(ptr: NonNull<GcBox<'gc, T>>) -> ArenaGc<'gc, T> {
+    ArenaGc { ptr, _invariant: PhantomData }
+  }
+
+  /// Returns `true` if both pointers point to the same value.
+  pub fn ptr_eq(this: &ArenaGc<'gc, T>, other: &ArenaGc<'gc, T>) -> bool {
+    this.ptr == other.ptr
+  }
+
+  fn inner(&self) -> &GcBox<'gc, T> {
+    unsafe { self.ptr.as_ref() }
+  }
+}
+
+impl<'gc, T: Trace> Finalize for ArenaGc<'gc, T> {}
+
+impl<'gc, T: Trace> HeapSize for ArenaGc<'gc, T> {}
+
+unsafe impl<'gc, T: Trace> Trace for ArenaGc<'gc, T> {
+  /// Marks the value in the `GcBox` as reachable.
+  unsafe fn mark(&self) {
+    self.inner().mark_box();
+  }
+}
+
+/// The `Deref` implementation allows to use the value's methods directly on the `ArenaGc` pointer.
+impl<'gc, T: Trace> Deref for ArenaGc<'gc, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    self.inner().value()
+  }
+}
+
+impl<'gc, T: Trace + 'gc> Clone for ArenaGc<'gc, T> {
+  fn clone(&self) -> ArenaGc<'gc, T> {
+    *self
+  }
+}
+
+impl<'gc, T: Trace + 'gc> Copy for ArenaGc<'gc, T> {}
@@ -0,0 +1,174 @@
+use ::std::cmp::max;
+use ::std::fmt::Debug;
+use ::std::time::{Duration, Instant};
+
+/// Summary of the heap of a `GcScope`, passed to its `CollectionPolicy` before each allocation.
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default)]
+pub struct GcHeapInfo {
+  /// Number of bytes currently allocated in the scope.
+  pub allocated_bytes: usize,
+
+  /// Number of values currently allocated in the scope.
+  pub allocated_objects: usize,
+
+  /// Number of bytes of external memory reported with `GcScope::report_external_alloc`, and not
+  /// freed yet.
+  pub external_bytes: usize,
+
+  /// Number of bytes allocated since the last collection, including the growth of the external
+  /// memory.
+  pub bytes_since_collection: usize,
+
+  /// Number of values allocated since the last collection.
+  pub allocations_since_collection: usize,
+
+  /// Number of bytes allocated in the nursery (always 0 if the scope is not generational).
+  pub nursery_bytes: usize,
+
+  /// Number of values allocated in the nursery (always 0 if the scope is not generational).
+  pub nursery_objects: usize,
+}
+
+/// Kind of garbage collection.
+#[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum GcCollectionKind {
+  /// Collection of the nursery of a generational scope.
+  Minor,
+  /// Collection of all the values of the scope.
+  #[default]
+  Major,
+}
+
+/// Outcome of a garbage collection, passed to the `CollectionPolicy` of the scope.
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GcCollectionResult {
+  /// Kind of the collection.
+  pub kind: GcCollectionKind,
+
+  /// Number of values freed by the collection.
+  pub freed_objects: usize,
+
+  /// Number of bytes freed by the collection.
+  pub freed_bytes: usize,
+
+  /// Number of values surviving the collection.
+  pub live_objects: usize,
+
+  /// Number of bytes surviving the collection.
+  pub live_bytes: usize,
+
+  /// Number of bytes of external memory reported to the scope at the end of the collection.
+  pub external_bytes: usize,
+
+  /// Time spent looking for the reachable values, summed over the steps of the collection.
+  pub mark_duration: Duration,
+
+  /// Time spent finalizing, dropping and freeing the unreachable values, summed over the steps of
+  /// the collection.
+  pub sweep_duration: Duration,
+}
+
+/// Decides when a `GcScope` collects garbage on its own.
+///
+/// The policy is asked before every allocation (and on each call to `GcScope::maybe_collect`)
+/// and is notified of the result of every collection, including the explicit ones.
+pub trait CollectionPolicy: Debug {
+  /// Returns `true` if garbage should be collected now.
+  fn should_collect(&mut self, heap: &GcHeapInfo) -> bool;
+
+  /// Called after every collection.
+  fn on_collect(&mut self, _result: &GcCollectionResult) {}
+
+  /// Returns the number of allocated bytes triggering the next collection, if the policy uses a
+  /// threshold. Its changes are reported with the `tracing` feature.
+  fn threshold(&self) -> Option<usize> {
+    None
+  }
+}
+
+/// Never collects garbage automatically: garbage is only collected when asked explicitly.
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default)]
+pub struct NeverPolicy;
+
+impl CollectionPolicy for NeverPolicy {
+  fn should_collect(&mut self, _heap: &GcHeapInfo) -> bool {
+    false
+  }
+}
+
+/// Collects garbage once the allocated bytes cross a threshold.
+///
+/// After each collection, the threshold is set to the surviving bytes multiplied by the growth
+/// factor. It never goes below its initial value. The reported external memory counts as allocated
+/// bytes.
+#[derive(PartialOrd, PartialEq, Copy, Clone, Debug)]
+pub struct ThresholdPolicy {
+  initial_threshold: usize,
+  threshold: usize,
+  growth_factor: f64,
+}
+
+impl ThresholdPolicy {
+  pub fn new(threshold: usize, growth_factor: f64) -> ThresholdPolicy {
+    ThresholdPolicy { initial_threshold: threshold, threshold, growth_factor }
+  }
+}
+
+impl CollectionPolicy for ThresholdPolicy {
+  fn should_collect(&mut self, heap: &GcHeapInfo) -> bool {
+    heap.allocated_bytes.saturating_add(heap.external_bytes) >= self.threshold
+  }
+
+  fn on_collect(&mut self, result: &GcCollectionResult) {
+    let live_bytes: usize = result.live_bytes.saturating_add(result.external_bytes);
+    let grown_threshold: f64 = live_bytes as f64 * self.growth_factor;
+    self.threshold = max(self.initial_threshold, grown_threshold as usize);
+  }
+
+  fn threshold(&self) -> Option<usize> {
+    Some(self.threshold)
+  }
+}
+
+/// Collects garbage every `allocations` allocations.
+#[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Debug)]
+pub struct AllocationCountPolicy {
+  allocations: usize,
+}
+
+impl AllocationCountPolicy {
+  pub fn new(allocations: usize) -> AllocationCountPolicy {
+    AllocationCountPolicy { allocations }
+  }
+}
+
+impl CollectionPolicy for AllocationCountPolicy {
+  fn should_collect(&mut self, heap: &GcHeapInfo) -> bool {
+    heap.allocations_since_collection >= self.allocations
+  }
+}
+
+/// Collects garbage once `interval` has elapsed since the last collection.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct IntervalPolicy {
+  interval: Duration,
+  last_collection: Instant,
+}
+
+impl IntervalPolicy {
+  pub fn new(interval: Duration) -> IntervalPolicy {
+    IntervalPolicy { interval, last_collection: Instant::now() }
+  }
+}
+
+impl CollectionPolicy for IntervalPolicy {
+  fn should_collect(&mut self, _heap: &GcHeapInfo) -> bool {
+    self.last_collection.elapsed() >= self.interval
+  }
+
+  fn on_collect(&mut self, _result: &GcCollectionResult) {
+    self.last_collection = Instant::now();
+  }
+}
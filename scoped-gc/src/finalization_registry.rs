@@ -0,0 +1,40 @@
+use ::std::any::Any;
+use ::std::cell::Cell;
+use ::std::mem;
+use ::std::rc::Rc;
+
+/// A registered held value, with the weak flag of its target.
+type Registration = (Rc<Cell<bool>>, Box<dyn Any>);
+
+/// Internal struct for the held values registered with `GcScope::register_finalizer`.
+///
+/// Each held value is kept with the weak flag of its target. Once a collection clears the flag,
+/// the held value is moved to the queue drained by `GcScope::take_finalized`.
+#[derive(Debug, Default)]
+pub(crate) struct FinalizationRegistry {
+  entries: Vec<Registration>,
+  // Held values of the collected targets, in the order they were found unreachable
+  finalized: Vec<Box<dyn Any>>,
+}
+
+impl FinalizationRegistry {
+  pub(crate) fn register(&mut self, alive: Rc<Cell<bool>>, held: Box<dyn Any>) {
+    self.entries.push((alive, held));
+  }
+
+  /// Moves the held values of the targets found unreachable to the queue.
+  pub(crate) fn queue_collected(&mut self) {
+    let entries: Vec<Registration> = mem::take(&mut self.entries);
+    for (alive, held) in entries {
+      if alive.get() {
+        self.entries.push((alive, held));
+      } else {
+        self.finalized.push(held);
+      }
+    }
+  }
+
+  pub(crate) fn take_finalized(&mut self) -> Vec<Box<dyn Any>> {
+    mem::take(&mut self.finalized)
+  }
+}
@@ -0,0 +1,12 @@
+/// Cleanup run on the values managed by the garbage collector once they are unreachable.
+///
+/// When a collection finds values unreachable, it first calls `finalize` on all of them, then
+/// drops all of them, and only then frees their memory. Unlike in `Drop`, the `Gc` pointers of the
+/// value can be dereferenced in `finalize`: the values they point to are not dropped yet, even if
+/// they are unreachable too. Dereferencing a `Gc` pointer while the values are dropped panics.
+///
+/// `finalize` is only called on the values allocated in a scope, not on the values they own.
+/// It is also called on all the remaining values when the scope is dropped.
+pub trait Finalize {
+  fn finalize(&self) {}
+}
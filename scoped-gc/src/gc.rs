@@ -1,7 +1,11 @@
-use ::std::cell::Cell;
 use ::std::ops::Deref;
+#[cfg(feature = "debug")]
+use ::std::panic::Location;
 use ::std::ptr::NonNull;
+use finalize::Finalize;
 use gc_box::GcBox;
+use gc_weak::GcWeak;
+use heap_size::HeapSize;
 use trace::Trace;
 
 /// A smart pointer to a value managed by a garbage-collector
@@ -11,54 +15,66 @@ use trace::Trace;
 /// This pointer can only be used during the lifetime of the corresponding garbage-collected
 /// scope (represented by the lifetime `'gc`).
 ///
-/// By default, a `Gc` pointer acts as a root for its value: as long as the `Gc` is on the stack
-/// its value is kept alive.
-/// A `Gc` is unrooted only when it is moved (transitively) inside another `Gc`.
+/// Each `Gc` pointer increments the reference count of its value. When collecting garbage, the
+/// references coming from other managed values are subtracted: the remaining ones come from
+/// outside of the managed memory (for example from the stack) and keep the value alive.
+/// Creating, cloning, moving or dropping a `Gc` is cheap: it never traverses the value.
 #[derive(Debug)]
 pub struct Gc<'gc, T: Trace + 'gc> {
   ptr: NonNull<GcBox<'gc, T>>,
-  rooted: Cell<bool>,
 }
 
 impl<'gc, T: Trace + 'gc> Gc<'gc, T> {
+  /// Wraps a pointer to a new box: its reference count already accounts for this `Gc`.
   pub(crate) fn new(ptr: NonNull<GcBox<'gc, T>>) -> Gc<'gc, T> {
-    Gc { ptr, rooted: Cell::new(true) }
+    Gc { ptr }
+  }
+
+  pub(crate) fn box_ptr(this: &Gc<'gc, T>) -> NonNull<GcBox<'gc, T>> {
+    this.ptr
+  }
+
+  /// Creates a `GcWeak` pointer to the value of `this`.
+  ///
+  /// It is recommended to use it as `Gc::downgrade(&gc)` to avoid confusion with the methods of
+  /// the inner value.
+  pub fn downgrade(this: &Gc<'gc, T>) -> GcWeak<'gc, T> {
+    GcWeak::new(this.ptr, this.inner().weak_flag())
+  }
+
+  /// Returns the location of the code allocating the value of `this` (with the `debug` feature).
+  #[cfg(feature = "debug")]
+  pub fn allocation_site(this: &Gc<'gc, T>) -> &'static Location<'static> {
+    this.inner().location
   }
 }
 
 /// An internal trait to get a reference for the box containing a garbage-collected value.
 trait GcBoxPtr<'gc, T: Trace + 'gc> {
-  fn inner(&self) -> &GcBox<T>;
+  fn inner(&self) -> &GcBox<'gc, T>;
 }
 
 impl<'gc, T: Trace + 'gc> GcBoxPtr<'gc, T> for Gc<'gc, T> {
-  fn inner(&self) -> &GcBox<T> {
+  fn inner(&self) -> &GcBox<'gc, T> {
     unsafe { self.ptr.as_ref() }
   }
 }
 
+impl<'gc, T: Trace> Finalize for Gc<'gc, T> {}
+
+/// The value is owned by its box: it is not counted in the heap size of the pointer.
+impl<'gc, T: Trace> HeapSize for Gc<'gc, T> {}
+
 unsafe impl<'gc, T: Trace> Trace for Gc<'gc, T> {
-  /// Marks the value in the `GcBox` as reachable.
+  /// Signals the `GcBox` that it is referenced by the value being traced.
   ///
-  /// The `mark` signal will be propagated further in the object graph unless the box was already
-  /// marked (to avoid infinite loops on cycles, or redundant traversals).
+  /// While counting, this increments the internal reference count of the box. While marking,
+  /// unless the box was already marked (to avoid infinite loops on cycles, or redundant
+  /// traversals), it is queued in the worklist of the collector: the `mark` signal will be
+  /// propagated further in the object graph when the collector traces the value.
   unsafe fn mark(&self) {
     self.inner().mark_box();
   }
-
-  /// Tags this `Gc` pointer as a root for its value.
-  unsafe fn root(&self) {
-    debug_assert!(!self.rooted.get());
-    self.inner().inc_roots();
-    self.rooted.set(true);
-  }
-
-  /// Untags this `Gc` pointer as a root for its value.
-  unsafe fn unroot(&self) {
-    debug_assert!(self.rooted.get());
-    self.inner().dec_roots();
-    self.rooted.set(false);
-  }
 }
 
 /// The `Deref` implementation allows to use the value's methods directly on the `Gc` pointer.
@@ -66,16 +82,14 @@ impl<'gc, T: Trace> Deref for Gc<'gc, T> {
   type Target = T;
 
   fn deref(&self) -> &T {
-    &self.inner().value
+    self.inner().value()
   }
 }
 
 
 impl<'gc, T: Trace> Drop for Gc<'gc, T> {
   fn drop(&mut self) {
-    if self.rooted.get() {
-      self.inner().dec_roots();
-    }
+    self.inner().dec_refs();
   }
 }
 
@@ -83,9 +97,12 @@ impl<'gc, T: Trace> Drop for Gc<'gc, T> {
 ///
 /// It is recommended to use it as `Gc::clone(&gc)` instead of `gc.clone()` to avoid confusion
 /// with the `clone` method of the inner value.
+///
+/// This panics if called while the collector drops unreachable values, for example from the `Drop`
+/// implementation of a managed value: the pointer could outlive the box.
 impl<'gc, T: Trace + 'gc> Clone for Gc<'gc, T> {
   fn clone(&self) -> Gc<'gc, T> {
-    self.inner().inc_roots();
-    Gc { ptr: self.ptr, rooted: Cell::new(true) }
+    self.inner().inc_refs();
+    Gc { ptr: self.ptr }
   }
 }
@@ -1,9 +1,22 @@
+use ::std::error::Error;
+use ::std::fmt;
+
 /// Represents an allocation error
-///
-/// This is currently unused but may be returned once more checks are implemented around the
-/// garbage-collector.
 #[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Debug)]
 pub enum GcAllocErr {
   /// Signals that the garbage collector exhausted all its available memory.
+  ///
+  /// It is returned when an allocation would exceed the byte limit of the scope, even after
+  /// collecting garbage.
   Exhausted,
 }
+
+impl fmt::Display for GcAllocErr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      GcAllocErr::Exhausted => f.write_str("garbage-collected memory exhausted"),
+    }
+  }
+}
+
+impl Error for GcAllocErr {}
@@ -0,0 +1,161 @@
+use ::std::cell::RefCell;
+use ::std::marker::PhantomData;
+use ::std::mem;
+use ::std::ptr;
+use arena_gc::ArenaGc;
+use collection_policy::{CollectionPolicy, ThresholdPolicy};
+use gc_alloc_err::GcAllocErr;
+use gc_config::GcConfig;
+use gc_scope::GcState;
+use trace::Trace;
+
+/// Names the type of the root of a `GcArena` for any lifetime `'gc`.
+///
+/// The root type depends on the lifetime of the mutation (its `ArenaGc` pointers are branded with
+/// it), so it is named through this trait. Implement it on a marker type:
+///
+/// ```
+/// use scoped_gc::{ArenaGc, GcRefCell, Rootable};
+///
+/// struct NodesRoot;
+///
+/// impl<'gc> Rootable<'gc> for NodesRoot {
+///   type Root = ArenaGc<'gc, GcRefCell<Vec<ArenaGc<'gc, String>>>>;
+/// }
+/// ```
+pub trait Rootable<'gc> {
+  type Root: Trace + 'gc;
+}
+
+/// Handle to allocate values during `GcArena::mutate`.
+///
+/// Its lifetime `'gc` is unique to the mutation: the `ArenaGc` pointers it creates can't escape it,
+/// except by being stored in the values reachable from the root of the arena.
+pub struct MutationContext<'gc> {
+  state: &'gc RefCell<GcState<'gc>>,
+  // `'gc` is invariant so contexts of different mutations can't be mixed
+  _invariant: PhantomData<::std::cell::Cell<&'gc ()>>,
+}
+
+impl<'gc> MutationContext<'gc> {
+  /// Allocates `value` in the arena and returns an `ArenaGc` pointer to it.
+  ///
+  /// This never collects garbage: collections only run between mutations.
+  #[cfg_attr(feature = "debug", track_caller)]
+  pub fn alloc<T: Trace + 'gc>(self, value: T) -> Result<ArenaGc<'gc, T>, GcAllocErr> {
+    self.state.borrow_mut()
+      .alloc(value)
+      .map(ArenaGc::new)
+  }
+}
+
+impl<'gc> Clone for MutationContext<'gc> {
+  fn clone(&self) -> MutationContext<'gc> {
+    *self
+  }
+}
+
+impl<'gc> Copy for MutationContext<'gc> {}
+
+/// A garbage-collected arena, alternative to `GcScope` in the style of the `gc-arena` crate.
+///
+/// Values are allocated and used inside calls to `GcArena::mutate`, with a lifetime `'gc` unique
+/// to each call. Garbage is only collected between two calls, with `collect_garbage` or
+/// `maybe_collect`: at that point the only values still in use are the values reachable from the
+/// root of the arena. The pointers to the values, `ArenaGc`, are then not counted.
+///
+/// The root is created by the closure passed to `GcArena::new`, and dropped with the arena.
+///
+/// ```
+/// use scoped_gc::{ArenaGc, GcArena, GcRefCell, Rootable};
+///
+/// struct NamesRoot;
+///
+/// impl<'gc> Rootable<'gc> for NamesRoot {
+///   type Root = ArenaGc<'gc, GcRefCell<Vec<ArenaGc<'gc, String>>>>;
+/// }
+///
+/// let mut arena: GcArena<NamesRoot> = GcArena::new(|mc| mc.alloc(GcRefCell::new(Vec::new())).unwrap());
+/// arena.mutate(|mc, root| {
+///   let name = mc.alloc(String::from("Hello, World!")).unwrap();
+///   root.borrow_mut().push(name);
+/// });
+/// arena.collect_garbage();
+/// arena.mutate(|_, root| assert_eq!(*root.borrow()[0], "Hello, World!"));
+/// ```
+///
+/// The pointers can't escape the mutation where they were allocated:
+///
+/// ```compile_fail
+/// use scoped_gc::{ArenaGc, GcArena, Rootable};
+///
+/// struct UnitRoot;
+///
+/// impl<'gc> Rootable<'gc> for UnitRoot {
+///   type Root = ();
+/// }
+///
+/// let mut arena: GcArena<UnitRoot> = GcArena::new(|_| ());
+/// let name = arena.mutate(|mc, _| mc.alloc(String::from("Hello, World!")).unwrap());
+/// arena.collect_garbage();
+/// println!("{}", *name);
+/// ```
+pub struct GcArena<R: for<'gc> Rootable<'gc>> {
+  // Declared before `state`, so it is dropped before the boxes are freed
+  root: <R as Rootable<'static>>::Root,
+  state: RefCell<GcState<'static>>,
+}
+
+impl<R: for<'gc> Rootable<'gc>> GcArena<R> {
+  /// Creates an arena with the default configuration, and its root built by `f`.
+  pub fn new<F>(f: F) -> GcArena<R>
+    where F: for<'gc> FnOnce(MutationContext<'gc>) -> <R as Rootable<'gc>>::Root {
+    let config: GcConfig = GcConfig::default();
+    GcArena::with_policy(ThresholdPolicy::new(config.threshold.unwrap(), config.growth_factor), f)
+  }
+
+  /// Creates an arena asking `policy` when `maybe_collect` collects garbage, and its root built by
+  /// `f`.
+  pub fn with_policy<P, F>(policy: P, f: F) -> GcArena<R>
+    where P: CollectionPolicy + 'static, F: for<'gc> FnOnce(MutationContext<'gc>) -> <R as Rootable<'gc>>::Root {
+    let mut state: GcState<'static> = GcState::new(Box::new(policy));
+    state.arena = true;
+    let state: RefCell<GcState<'static>> = RefCell::new(state);
+    let root: <R as Rootable<'static>>::Root = {
+      let root = f(context(&state));
+      // The lifetime of the root is erased until the next mutation
+      let erased = unsafe { ptr::read(&root as *const <R as Rootable<'_>>::Root as *const <R as Rootable<'static>>::Root) };
+      mem::forget(root);
+      erased
+    };
+    GcArena { root, state }
+  }
+
+  /// Runs `f` with a context to allocate values and the root of the arena.
+  pub fn mutate<F, T>(&self, f: F) -> T
+    where F: for<'gc> FnOnce(MutationContext<'gc>, &'gc <R as Rootable<'gc>>::Root) -> T {
+    let root: &<R as Rootable<'_>>::Root = unsafe {
+      &*(&self.root as *const <R as Rootable<'static>>::Root as *const <R as Rootable<'_>>::Root)
+    };
+    f(context(&self.state), root)
+  }
+
+  /// Collects all the values that are not reachable from the root.
+  pub fn collect_garbage(&mut self) {
+    self.state.borrow_mut().collect_garbage_from(&self.root)
+  }
+
+  /// Collects garbage if the policy of the arena asks for it, and returns whether it did.
+  pub fn maybe_collect(&mut self) -> bool {
+    self.state.borrow_mut().maybe_collect_from(&self.root)
+  }
+}
+
+/// Returns a context for the state, branded with the lifetime of the borrow.
+fn context<'gc>(state: &'gc RefCell<GcState<'static>>) -> MutationContext<'gc> {
+  // Only the lifetime of the state changes: the values it owns are valid during the borrow.
+  let state: &'gc RefCell<GcState<'gc>> = unsafe {
+    &*(state as *const RefCell<GcState<'static>>).cast::<RefCell<GcState<'gc>>>()
+  };
+  MutationContext { state, _invariant: PhantomData }
+}
@@ -1,5 +1,12 @@
-use ::std::cell::Cell;
+use ::std::any::TypeId;
+use ::std::cell::{Cell, RefCell};
+use ::std::marker::PhantomData;
+use ::std::mem::{self, ManuallyDrop};
+#[cfg(feature = "debug")]
+use ::std::panic::Location;
 use ::std::ptr::NonNull;
+use ::std::rc::Rc;
+use gc_marker::GcMarker;
 use trace::Trace;
 
 /// Internal struct containing the values allocated by the garbage collector, with their metadata.
@@ -7,36 +14,146 @@ use trace::Trace;
 /// This struct is heap-allocated during `GcScope::alloc`.
 #[derive(Debug)]
 pub(crate) struct GcBox<'gc, T: Trace + ? Sized + 'gc> {
-  /// A counter for the `Gc` pointers or `GcRefMut` acting as roots for this value.
+  /// A counter for the `Gc` pointers to this value, wherever they are.
+  pub(crate) refs: Cell<usize>,
+
+  /// A counter for the `Gc` pointers to this value found in other boxes, computed at the start of
+  /// each collection.
   ///
-  /// Boxes with a non-zero root count act as starting points for the "mark" phase of the
-  /// garbage collector.
-  pub(crate) roots: Cell<usize>,
+  /// Boxes with more references than internal references are pointed to from outside of the
+  /// managed memory: they act as starting points for the "mark" phase of the garbage collector.
+  pub(crate) internal_refs: Cell<usize>,
 
   /// A boolean used during the "mark" phase of the garbage-collection to signal that this box is
   /// still reachable.
   pub(crate) marked: Cell<bool>,
 
+  /// A boolean signaling that this box is in the nursery: it was allocated since the last
+  /// collection of a generational scope.
+  pub(crate) young: Cell<bool>,
+
+  /// A boolean signaling that this box was found unreachable and its value finalized by the current
+  /// collection.
+  ///
+  /// Finalizers may make such a box reachable again: it is then moved back to the live boxes.
+  pub(crate) finalized: Cell<bool>,
+
+  /// A boolean signaling that the value of this box was dropped.
+  ///
+  /// A destructor may move a `Gc` pointer out of its value: the box it points to is then kept
+  /// until the pointer is dropped, but its value can't be used.
+  pub(crate) value_dropped: Cell<bool>,
+
+  /// The number of bytes accounted for this box by its scope: the size of the box and the heap
+  /// memory owned by its value, measured when it was allocated or last survived a collection.
+  pub(crate) size: Cell<usize>,
+
+  /// The name of the type of the value, reported when the box is freed.
+  pub(crate) type_name: &'static str,
+
+  /// Returns the type id of the value, ignoring its lifetimes (see `erased_type_id`).
+  pub(crate) type_id: fn() -> TypeId,
+
+  /// The location of the code allocating the value.
+  #[cfg(feature = "debug")]
+  pub(crate) location: &'static Location<'static>,
+
+  /// A boolean signaling that the allocation of this box was sampled by the heap profiler.
+  #[cfg(feature = "heap-profiler")]
+  pub(crate) sampled: Cell<bool>,
+
+  /// The flag shared with the `GcWeak` pointers to this box, if any.
+  ///
+  /// It is cleared once the box is found unreachable, before any value is dropped.
+  pub(crate) weak: RefCell<Option<Rc<Cell<bool>>>>,
+
+  /// The marker of the scope owning this box.
+  pub(crate) marker: NonNull<GcMarker<'gc>>,
+
   /// A fat pointer (trait object) to the next `GcBox` if any.
   pub(crate) next: Option<NonNull<GcBox<'gc, dyn Trace>>>,
 
   /// The value the user allocated.
-  pub(crate) value: T,
+  ///
+  /// It is dropped by the collector before the box is freed: all the unreachable values are
+  /// finalized, then dropped, before any box is freed, so their `Gc` pointers can release their
+  /// reference.
+  pub(crate) value: ManuallyDrop<T>,
 }
 
-impl<'gc, T: Trace + ? Sized + 'gc> GcBox<'gc, T> {
+impl<'gc, T: Trace + 'gc> GcBox<'gc, T> {
+  /// Signals that this box is pointed to by the value being traced.
+  ///
+  /// The box is either counted as an internal reference, or queued in the gray worklist of its
+  /// marker: its value is traced later, by the collector, instead of recursively.
   pub fn mark_box(&self) {
-    if !self.marked.get() {
-      self.marked.set(true);
-      unsafe { self.value.mark() }
+    self.marker().visit(NonNull::from(self as &GcBox<'gc, dyn Trace>));
+  }
+
+  /// Returns the value of the box.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the collector is dropping values, or if the value is dropped: the value may already
+  /// be dropped.
+  pub fn value(&self) -> &T {
+    assert!(!self.marker().dropping.get(), "a `Gc` pointer can't be dereferenced while unreachable values are dropped");
+    assert!(!self.value_dropped.get(), "a `Gc` pointer can't be dereferenced once its value is dropped");
+    &self.value
+  }
+
+  /// Counts a new `Gc` pointer to the box.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the collector is dropping values: the box may be freed once they are dropped.
+  pub fn inc_refs(&self) {
+    assert!(!self.marker().dropping.get(), "a `Gc` pointer can't be cloned while unreachable values are dropped");
+    self.refs.set(self.refs.get().checked_add(1).unwrap());
+  }
+
+  pub fn dec_refs(&self) {
+    self.refs.set(self.refs.get().checked_sub(1).unwrap());
+  }
+
+  /// Returns the flag shared with the `GcWeak` pointers to this box, creating it if needed.
+  ///
+  /// Once the value is finalized or dropped, the weak references to the box are already cleared:
+  /// the returned flag is cleared too, and the box is not registered again.
+  pub fn weak_flag(&self) -> Rc<Cell<bool>> {
+    let mut weak = self.weak.borrow_mut();
+    if weak.is_none() {
+      if self.finalized.get() || self.marker().dropping.get() {
+        return Rc::new(Cell::new(false));
+      }
+      self.marker().weak_boxes.borrow_mut().push(NonNull::from(self as &GcBox<'gc, dyn Trace>));
+      *weak = Some(Rc::new(Cell::new(true)));
     }
+    Rc::clone(weak.as_ref().unwrap())
+  }
+
+  fn marker(&self) -> &GcMarker<'gc> {
+    unsafe { self.marker.as_ref() }
   }
+}
 
-  pub fn inc_roots(&self) {
-    self.roots.set(self.roots.get().checked_add(1).unwrap())
+/// Returns the `TypeId` of `T`, ignoring its lifetimes: unlike `TypeId::of`, `T` does not have to
+/// be `'static`. Types differing only by their lifetimes have the same id.
+pub(crate) fn erased_type_id<T: ? Sized>() -> TypeId {
+  trait NonStaticAny {
+    fn get_type_id(&self) -> TypeId where Self: 'static;
   }
 
-  pub fn dec_roots(&self) {
-    self.roots.set(self.roots.get().checked_sub(1).unwrap())
+  impl<T: ? Sized> NonStaticAny for PhantomData<T> {
+    fn get_type_id(&self) -> TypeId where Self: 'static {
+      TypeId::of::<T>()
+    }
   }
+
+  let phantom_data: PhantomData<T> = PhantomData;
+  // The lifetimes are erased from the `TypeId`: extending them can't be observed
+  let phantom_data: &(dyn NonStaticAny + 'static) = unsafe {
+    mem::transmute::<&dyn NonStaticAny, &(dyn NonStaticAny + 'static)>(&phantom_data)
+  };
+  phantom_data.get_type_id()
 }
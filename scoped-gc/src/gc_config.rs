@@ -0,0 +1,33 @@
+/// Configures when a `GcScope` collects garbage on its own.
+///
+/// Use it with `GcScope::with_config`. `GcScope::new` uses the default configuration.
+/// It is a shorthand for a `ThresholdPolicy`, or a `NeverPolicy` when there is no threshold.
+#[derive(PartialOrd, PartialEq, Copy, Clone, Debug)]
+pub struct GcConfig {
+  /// Number of allocated bytes triggering the first automatic collection.
+  ///
+  /// `None` disables automatic collections: garbage is then only collected by explicit calls to
+  /// `GcScope::collect_garbage`.
+  pub threshold: Option<usize>,
+
+  /// Factor applied to the number of bytes surviving a collection to get the next threshold.
+  ///
+  /// The threshold never goes below its initial value.
+  pub growth_factor: f64,
+}
+
+impl GcConfig {
+  /// Returns a configuration where garbage is only collected when asked explicitly.
+  pub fn manual() -> GcConfig {
+    GcConfig { threshold: None, ..GcConfig::default() }
+  }
+}
+
+impl Default for GcConfig {
+  fn default() -> GcConfig {
+    GcConfig {
+      threshold: Some(1 << 20),
+      growth_factor: 2.0,
+    }
+  }
+}
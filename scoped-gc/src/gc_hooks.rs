@@ -0,0 +1,102 @@
+use ::std::fmt;
+use collection_policy::GcHeapInfo;
+use gc_near_limit_action::GcNearLimitAction;
+use gc_stats::GcStats;
+use trace::Trace;
+
+/// A callback called with a managed value.
+type ValueHook<'gc> = Box<dyn FnMut(&dyn Trace) + 'gc>;
+
+/// A callback called with the statistics of the scope at a step of a collection.
+type StatsHook<'gc> = Box<dyn FnMut(&GcStats) + 'gc>;
+
+/// A callback called with the type name and the size of a freed box.
+type FreeHook<'gc> = Box<dyn FnMut(&'static str, usize) + 'gc>;
+
+/// A callback called with the heap of the scope and its byte limit, once it is almost reached.
+type NearLimitHook<'gc> = Box<dyn FnMut(&GcHeapInfo, usize) -> GcNearLimitAction + 'gc>;
+
+/// Internal struct containing the callbacks registered on a `GcScope` to observe its collections.
+#[derive(Default)]
+pub(crate) struct GcHooks<'gc> {
+  /// Called with each value made reachable again by a finalizer.
+  pub(crate) resurrection: Option<ValueHook<'gc>>,
+
+  /// Called when the allocated bytes reach a share of the byte limit.
+  pub(crate) near_limit: Option<NearLimitHook<'gc>>,
+
+  /// Called when a collection starts looking for the reachable values.
+  pub(crate) before_mark: Option<StatsHook<'gc>>,
+
+  /// Called once all the reachable values are marked.
+  pub(crate) after_mark: Option<StatsHook<'gc>>,
+
+  /// Called before the unreachable values are finalized, once the weak references to them are
+  /// cleared.
+  pub(crate) before_sweep: Option<StatsHook<'gc>>,
+
+  /// Called once a collection is complete.
+  pub(crate) after_collection: Option<StatsHook<'gc>>,
+
+  /// Called with each box freed by a collection.
+  pub(crate) free: Option<FreeHook<'gc>>,
+}
+
+impl<'gc> GcHooks<'gc> {
+  pub(crate) fn on_resurrection(&mut self, value: &dyn Trace) {
+    if let Some(ref mut hook) = self.resurrection {
+      hook(value)
+    }
+  }
+
+  pub(crate) fn on_before_mark(&mut self, stats: &GcStats) {
+    if let Some(ref mut hook) = self.before_mark {
+      hook(stats)
+    }
+  }
+
+  pub(crate) fn on_after_mark(&mut self, stats: &GcStats) {
+    if let Some(ref mut hook) = self.after_mark {
+      hook(stats)
+    }
+  }
+
+  pub(crate) fn on_before_sweep(&mut self, stats: &GcStats) {
+    if let Some(ref mut hook) = self.before_sweep {
+      hook(stats)
+    }
+  }
+
+  pub(crate) fn on_after_collection(&mut self, stats: &GcStats) {
+    if let Some(ref mut hook) = self.after_collection {
+      hook(stats)
+    }
+  }
+
+  pub(crate) fn on_free(&mut self, type_name: &'static str, size: usize) {
+    if let Some(ref mut hook) = self.free {
+      hook(type_name, size)
+    }
+  }
+
+  pub(crate) fn on_near_limit(&mut self, heap: &GcHeapInfo, limit: usize) -> GcNearLimitAction {
+    match self.near_limit {
+      Some(ref mut hook) => hook(heap, limit),
+      None => GcNearLimitAction::Continue,
+    }
+  }
+}
+
+impl<'gc> fmt::Debug for GcHooks<'gc> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("GcHooks")
+      .field("resurrection", &self.resurrection.is_some())
+      .field("near_limit", &self.near_limit.is_some())
+      .field("before_mark", &self.before_mark.is_some())
+      .field("after_mark", &self.after_mark.is_some())
+      .field("before_sweep", &self.before_sweep.is_some())
+      .field("after_collection", &self.after_collection.is_some())
+      .field("free", &self.free.is_some())
+      .finish()
+  }
+}
@@ -0,0 +1,182 @@
+use ::std::cell::{Cell, RefCell};
+use ::std::ptr::NonNull;
+use ::std::rc::{Rc, Weak};
+use gc_box::GcBox;
+use gc_weak_map::Ephemerons;
+use trace::Trace;
+
+thread_local! {
+  /// Number of scopes of the current thread with a collection in progress.
+  static ACTIVE_COLLECTIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Returns `true` if a scope of the current thread has a collection in progress.
+///
+/// The write barrier of `GcRefCell` is only needed in this case.
+pub(crate) fn is_collection_active() -> bool {
+  ACTIVE_COLLECTIONS.with(|active| active.get() > 0)
+}
+
+/// Phase of the garbage collector.
+#[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Debug)]
+pub(crate) enum GcPhase {
+  /// No collection is in progress.
+  Idle,
+  /// Counting the references between boxes.
+  Count,
+  /// Looking for rooted boxes: boxes with more references than counted.
+  Roots,
+  /// Propagating the "mark" signal from the gray boxes.
+  Mark,
+  /// Finalizing the values of the boxes that were not marked.
+  Sweep,
+  /// Marking the finalized boxes reachable again once their finalizers ran.
+  Resurrect,
+  /// Dropping the finalized values, then freeing their boxes.
+  Drop,
+}
+
+/// Internal struct containing the state of the collector shared with the boxes.
+///
+/// Each `GcBox` points to the marker of its scope, so boxes can be visited without access to the
+/// (borrowed) `GcState`: when the collector counts the references between boxes, when the "mark"
+/// signal reaches them, and when a `GcRefCell` is mutated during an incremental collection (write
+/// barrier).
+///
+/// This struct is heap-allocated by `GcState` so its address is stable.
+#[derive(Debug)]
+pub(crate) struct GcMarker<'gc> {
+  /// Current phase of the collector.
+  phase: Cell<GcPhase>,
+
+  /// `true` during a minor collection: only the young boxes are counted and marked.
+  pub(crate) minor: Cell<bool>,
+
+  /// `true` while the collector counts the references of a value: visited boxes increment their
+  /// internal count instead of being shaded.
+  pub(crate) counting: Cell<bool>,
+
+  /// `true` while the collector drops values: `Gc` pointers can't be dereferenced.
+  pub(crate) dropping: Cell<bool>,
+
+  /// `true` while a collection hook runs: allocating in the scope panics.
+  pub(crate) locked: Cell<bool>,
+
+  /// Marked boxes whose value was not traced yet.
+  pub(crate) gray: RefCell<Vec<NonNull<GcBox<'gc, dyn Trace>>>>,
+
+  /// Boxes with a weak flag, to clear once they are found unreachable.
+  pub(crate) weak_boxes: RefCell<Vec<NonNull<GcBox<'gc, dyn Trace>>>>,
+
+  /// Tables of the weak maps reached during the current collection.
+  pub(crate) ephemerons: RefCell<Vec<Rc<dyn Ephemerons<'gc> + 'gc>>>,
+
+  /// Tables of all the weak maps with keys in the scope, reached or not: the entries whose key is
+  /// collected are removed from each of them.
+  pub(crate) weak_maps: RefCell<Vec<Weak<dyn Ephemerons<'gc> + 'gc>>>,
+
+  /// Number of bytes of external memory reported to the scope.
+  ///
+  /// It is stored here so destructors can report freed memory while the scope collects garbage.
+  pub(crate) external_bytes: Cell<usize>,
+
+  /// Linked-list of the boxes allocated while the scope was collecting garbage (from a destructor,
+  /// a finalizer or a hook), added to the scope by the collector once it is done.
+  pub(crate) pending: Cell<Option<NonNull<GcBox<'gc, dyn Trace>>>>,
+}
+
+impl<'gc> GcMarker<'gc> {
+  pub(crate) fn new() -> GcMarker<'gc> {
+    GcMarker {
+      phase: Cell::new(GcPhase::Idle),
+      minor: Cell::new(false),
+      counting: Cell::new(false),
+      dropping: Cell::new(false),
+      locked: Cell::new(false),
+      gray: RefCell::new(Vec::new()),
+      weak_boxes: RefCell::new(Vec::new()),
+      ephemerons: RefCell::new(Vec::new()),
+      weak_maps: RefCell::new(Vec::new()),
+      external_bytes: Cell::new(0),
+      pending: Cell::new(None),
+    }
+  }
+
+  pub(crate) fn phase(&self) -> GcPhase {
+    self.phase.get()
+  }
+
+  /// Updates the phase, keeping track of the scopes with a collection in progress.
+  pub(crate) fn set_phase(&self, phase: GcPhase) {
+    let was_idle: bool = self.phase.get() == GcPhase::Idle;
+    let is_idle: bool = phase == GcPhase::Idle;
+    if was_idle && !is_idle {
+      ACTIVE_COLLECTIONS.with(|active| active.set(active.get() + 1));
+    } else if !was_idle && is_idle {
+      ACTIVE_COLLECTIONS.with(|active| active.set(active.get() - 1));
+    }
+    self.phase.set(phase);
+  }
+
+  /// Returns `true` if the collector is looking for reachable boxes.
+  pub(crate) fn is_marking(&self) -> bool {
+    match self.phase.get() {
+      GcPhase::Count | GcPhase::Roots | GcPhase::Mark => true,
+      GcPhase::Idle | GcPhase::Sweep | GcPhase::Resurrect | GcPhase::Drop => false,
+    }
+  }
+
+  /// Handles a `Gc` pointer reached while tracing a value: counts it as an internal reference or
+  /// shades its box.
+  pub(crate) fn visit(&self, gc_box_ptr: NonNull<GcBox<'gc, dyn Trace>>) {
+    if self.counting.get() {
+      let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+      if self.minor.get() && !gc_box.young.get() {
+        return;
+      }
+      if self.phase.get() == GcPhase::Resurrect && !gc_box.finalized.get() {
+        return;
+      }
+      gc_box.internal_refs.set(gc_box.internal_refs.get() + 1);
+    } else {
+      self.shade(gc_box_ptr);
+    }
+  }
+
+  /// Returns `true` if the box was reached by the current collection: it is marked, or it is old
+  /// during a minor collection.
+  pub(crate) fn is_reached(&self, gc_box: &GcBox<'gc, dyn Trace>) -> bool {
+    gc_box.marked.get() || (self.minor.get() && !gc_box.young.get())
+  }
+
+  /// Marks the box and queues it for tracing, if it was not marked yet.
+  ///
+  /// This does nothing outside of the marking phases, or for old boxes during a minor collection.
+  /// During the "resurrect" phase, only the finalized boxes are shaded. The boxes kept after their
+  /// value was dropped are never shaded.
+  pub(crate) fn shade(&self, gc_box_ptr: NonNull<GcBox<'gc, dyn Trace>>) {
+    let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+    if gc_box.value_dropped.get() {
+      return;
+    }
+    if self.phase.get() == GcPhase::Resurrect {
+      if !gc_box.finalized.get() {
+        return;
+      }
+    } else if !self.is_marking() {
+      return;
+    }
+    if self.minor.get() && !gc_box.young.get() {
+      return;
+    }
+    if !gc_box.marked.get() {
+      gc_box.marked.set(true);
+      self.gray.borrow_mut().push(gc_box_ptr);
+    }
+  }
+
+  /// Removes a box from the gray worklist.
+  pub(crate) fn pop_gray(&self) -> Option<NonNull<GcBox<'gc, dyn Trace>>> {
+    self.gray.borrow_mut().pop()
+  }
+}
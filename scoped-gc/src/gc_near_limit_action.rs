@@ -0,0 +1,17 @@
+/// Decision of the near-heap-limit callback of a `GcScope`.
+///
+/// See `GcScope::set_near_limit_callback`.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum GcNearLimitAction {
+  /// Proceeds with the allocation.
+  Continue,
+  /// Collects garbage, then proceeds with the allocation.
+  ///
+  /// This lets the callback release some values (for example by clearing caches) and have them
+  /// freed right away.
+  Collect,
+  /// Replaces the byte limit of the scope, then proceeds with the allocation.
+  SetLimit(usize),
+  /// Fails the allocation with `GcAllocErr::Exhausted`.
+  Fail,
+}
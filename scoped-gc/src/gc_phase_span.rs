@@ -0,0 +1,61 @@
+use gc_marker::GcPhase;
+
+/// Internal struct holding the `tracing` span of the phase of the collection in progress.
+///
+/// The phases are grouped in three spans: "gc_mark" (count, roots and mark), "gc_sweep" (sweep and
+/// resurrect) and "gc_drop". The span is exited at the end of each collection step, so incremental
+/// collections report one span per phase and step. Without the `tracing` feature, this does
+/// nothing.
+#[derive(Debug, Default)]
+pub(crate) struct GcPhaseSpan {
+  #[cfg(feature = "tracing")]
+  current: Option<(GcPhaseGroup, ::tracing::span::EnteredSpan)>,
+}
+
+#[cfg(feature = "tracing")]
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+enum GcPhaseGroup {
+  Mark,
+  Sweep,
+  Drop,
+}
+
+#[cfg(feature = "tracing")]
+impl GcPhaseSpan {
+  /// Enters the span of `phase`, unless it is already entered.
+  pub(crate) fn enter(&mut self, phase: GcPhase, minor: bool) {
+    let group: Option<GcPhaseGroup> = match phase {
+      GcPhase::Idle => None,
+      GcPhase::Count | GcPhase::Roots | GcPhase::Mark => Some(GcPhaseGroup::Mark),
+      GcPhase::Sweep | GcPhase::Resurrect => Some(GcPhaseGroup::Sweep),
+      GcPhase::Drop => Some(GcPhaseGroup::Drop),
+    };
+    if self.current.as_ref().map(|current| current.0) == group {
+      return;
+    }
+    // The previous span is exited before entering the next one
+    self.current = None;
+    self.current = group.map(|group| {
+      let span = match group {
+        GcPhaseGroup::Mark => ::tracing::info_span!("gc_mark", minor),
+        GcPhaseGroup::Sweep => ::tracing::info_span!("gc_sweep", minor),
+        GcPhaseGroup::Drop => ::tracing::info_span!("gc_drop", minor),
+      };
+      (group, span.entered())
+    });
+  }
+
+  /// Exits the current span, if any.
+  pub(crate) fn exit(&mut self) {
+    self.current = None;
+  }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl GcPhaseSpan {
+  #[inline]
+  pub(crate) fn enter(&mut self, _phase: GcPhase, _minor: bool) {}
+
+  #[inline]
+  pub(crate) fn exit(&mut self) {}
+}
@@ -1,61 +1,55 @@
-use ::std::cell::{Cell, Ref, RefCell, RefMut};
+use ::std::cell::{Ref, RefCell, RefMut};
 use ::std::ops::{Deref, DerefMut};
+use finalize::Finalize;
+use gc_marker::is_collection_active;
+use heap_size::HeapSize;
 use super::trace::Trace;
 
 /// A `RefCell` wrapper compatible with garbage-collection.
 #[derive(Debug)]
 pub struct GcRefCell<T: Trace> {
-  rooted: Cell<bool>,
   ref_cell: RefCell<T>,
 }
 
 impl<T: Trace> GcRefCell<T> {
   pub fn new(value: T) -> GcRefCell<T> {
     GcRefCell {
-      rooted: Cell::new(true),
       ref_cell: RefCell::new(value),
     }
   }
 
-  pub fn borrow(&self) -> GcRef<T> {
+  pub fn borrow(&self) -> GcRef<'_, T> {
     GcRef { _ref: self.ref_cell.borrow() }
   }
 
-  pub fn borrow_mut(&self) -> GcRefMut<T> {
-    // Root the content of the cell for the duration of the mutable borrow, this will be restored
-    // once `GcRefMut` is dropped.
-    if !self.rooted.get() {
-      unsafe { self.ref_cell.borrow().root(); }
+  pub fn borrow_mut(&self) -> GcRefMut<'_, T> {
+    let ref_mut: RefMut<'_, T> = self.ref_cell.borrow_mut();
+    // Write barrier of incremental collections: the values referenced by the cell when it is
+    // borrowed are shaded, so the values moved out of the cell survive the collection in progress.
+    // The values moved into the cell still have their references from outside of the managed
+    // memory, they are found as roots.
+    if is_collection_active() {
+      unsafe { ref_mut.mark() }
     }
-    GcRefMut { rooted: &self.rooted, _ref: self.ref_cell.borrow_mut() }
+    GcRefMut { _ref: ref_mut }
   }
 }
 
-unsafe impl<T: Trace> Trace for GcRefCell<T> {
-  unsafe fn mark(&self) {
-    // If we can't borrow, it means that there is an active RefMut and the value is rooted
-    // (no need to trace)
-    match self.ref_cell.try_borrow() {
-      Ok(ref value) => value.mark(),
-      Err(_) => (),
-    }
-  }
+impl<T: Trace> Finalize for GcRefCell<T> {}
 
-  unsafe fn root(&self) {
-    assert!(!self.rooted.get());
-    self.rooted.set(true);
-    match self.ref_cell.try_borrow() {
-      Ok(ref value) => value.root(),
-      Err(_) => (),
-    }
+impl<T: Trace> HeapSize for GcRefCell<T> {
+  /// Returns 0 while the value is mutably borrowed: it is measured again by the next collection.
+  fn heap_size(&self) -> usize {
+    self.ref_cell.try_borrow().map(|value| value.heap_size()).unwrap_or(0)
   }
+}
 
-  unsafe fn unroot(&self) {
-    assert!(self.rooted.get());
-    self.rooted.set(false);
-    match self.ref_cell.try_borrow() {
-      Ok(ref value) => value.unroot(),
-      Err(_) => (),
+unsafe impl<T: Trace> Trace for GcRefCell<T> {
+  unsafe fn mark(&self) {
+    // If we can't borrow, it means that there is an active RefMut: the references it holds are
+    // not counted so its values are rooted (no need to trace)
+    if let Ok(ref value) = self.ref_cell.try_borrow() {
+      value.mark()
     }
   }
 }
@@ -73,7 +67,6 @@ impl<'a, T: Trace + 'a> Deref for GcRef<'a, T> {
 }
 
 pub struct GcRefMut<'a, T: Trace + 'a> {
-  rooted: &'a Cell<bool>,
   _ref: RefMut<'a, T>,
 }
 
@@ -90,12 +83,3 @@ impl<'a, T: Trace + 'a> DerefMut for GcRefMut<'a, T> {
     self._ref.deref_mut()
   }
 }
-
-impl<'a, T: Trace + 'a> Drop for GcRefMut<'a, T> {
-  fn drop(&mut self) {
-    // Restore the `rooted state` of the inner value before the call to `borrow_mut`
-    if !self.rooted.get() {
-      unsafe { self._ref.unroot(); }
-    }
-  }
-}
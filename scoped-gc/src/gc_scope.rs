@@ -1,115 +1,1584 @@
+use ::std::any::{Any, TypeId};
 use ::std::cell::{Cell, RefCell};
-use ::std::mem::{size_of, size_of_val};
+use ::std::collections::HashMap;
+use ::std::mem::{self, size_of, size_of_val, ManuallyDrop};
+use ::std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "debug")]
+use ::std::panic::Location;
 use ::std::ptr::NonNull;
+use ::std::rc::{Rc, Weak};
+use ::std::time::{Duration, Instant};
+use collection_policy::{CollectionPolicy, GcCollectionKind, GcCollectionResult, GcHeapInfo, NeverPolicy};
+use collection_policy::ThresholdPolicy;
+use finalization_registry::FinalizationRegistry;
 use gc::Gc;
 use gc_alloc_err::GcAllocErr;
-use gc_box::GcBox;
+use gc_box::{erased_type_id, GcBox};
+use gc_config::GcConfig;
+use gc_hooks::GcHooks;
+use gc_marker::{GcMarker, GcPhase};
+use gc_near_limit_action::GcNearLimitAction;
+use gc_phase_span::GcPhaseSpan;
+#[cfg(feature = "debug")]
+use gc_site_stats::GcSiteStats;
+use gc_stats::GcStats;
+use gc_type_stats::GcTypeStats;
+use gc_weak_map::Ephemerons;
+#[cfg(feature = "heap-profiler")]
+use heap_profiler::HeapProfiler;
 use trace::Trace;
 
+/// Work budget of the steps run by `GcScope::collect_until` between two checks of the deadline.
+const INCREMENTAL_STEP_BUDGET: usize = 256;
+
+/// Minimum size of the old generation triggering a major collection after a minor one.
+const MIN_MAJOR_THRESHOLD: usize = 1 << 20;
+
+/// Minimum size of the allocations reported by a `tracing` event.
+#[cfg(feature = "tracing")]
+const LARGE_ALLOCATION_BYTES: usize = 1 << 16;
+
 /// Defines a scope for garbage collection.
 ///
 /// It lets you allocate garbage-collected values. They can have cycles. Their reachability is
 /// tracked so they can be deallocated once unreachable.
 /// All the values are deallocated once the scope is dropped.
+///
+/// By default, garbage is collected automatically during `alloc` once enough bytes were allocated
+/// since the last collection. Use `GcScope::with_config` to tune or disable this behavior, or
+/// `GcScope::with_policy` to decide yourself when garbage is collected.
+///
+/// # Panic safety
+///
+/// If a `Trace::mark`, `Finalize::finalize` or `Drop` implementation panics during a collection,
+/// the scope is restored to a consistent state before the panic is propagated:
+///
+/// - A panic while looking for the reachable values aborts the collection: no value was finalized
+///   or dropped, and the next collection starts from scratch.
+/// - A panic while finalizing or dropping the unreachable values interrupts the collection: the
+///   value where it happened counts as finalized or dropped, and the next collection step resumes
+///   the interrupted collection.
+/// - A panic while looking for the values resurrected by finalizers keeps all the finalized values
+///   alive: they are finalized again once they are found unreachable again.
+///
+/// In all cases, the values that are still reachable are never dropped, each value is dropped at
+/// most once, and later collections work normally. A panic while the scope itself is dropped leaks
+/// the values that were not dropped yet.
 #[derive(Debug)]
 pub struct GcScope<'gc> {
   state: RefCell<GcState<'gc>>,
+  // Marker of the state, to allocate while the state is borrowed by a collection
+  marker: NonNull<GcMarker<'gc>>,
 }
 
 impl<'gc> GcScope<'gc> {
   pub fn new() -> GcScope<'gc> {
-    GcScope { state: RefCell::new(GcState::new()) }
+    GcScope::with_config(GcConfig::default())
+  }
+
+  /// Creates a scope collecting garbage according to `config`.
+  ///
+  /// Use `GcConfig::manual()` to only collect garbage on explicit calls to `collect_garbage`.
+  pub fn with_config(config: GcConfig) -> GcScope<'gc> {
+    match config.threshold {
+      Some(threshold) => GcScope::with_policy(ThresholdPolicy::new(threshold, config.growth_factor)),
+      None => GcScope::with_policy(NeverPolicy),
+    }
+  }
+
+  /// Creates a scope asking `policy` when to collect garbage.
+  pub fn with_policy<P: CollectionPolicy + 'static>(policy: P) -> GcScope<'gc> {
+    let state: GcState<'gc> = GcState::new(Box::new(policy));
+    let marker: NonNull<GcMarker<'gc>> = state.marker;
+    GcScope { state: RefCell::new(state), marker }
   }
 
   /// Allocates `value` in this garbage-collected scope and returns a `Gc` smart pointer to it.
+  ///
+  /// This may collect garbage before allocating, depending on the configuration of the scope.
+  ///
+  /// Values can be allocated while the scope is collecting garbage, for example from a destructor:
+  /// they are added to the scope once the collection is done. The values allocated while the scope
+  /// is dropped are dropped with it.
+  ///
+  /// # Errors
+  ///
+  /// Returns `GcAllocErr::Exhausted` if the allocation would exceed the byte limit of the scope,
+  /// even after a full collection. Allocations during a collection are not limited.
+  ///
+  /// # Panics
+  ///
+  /// Panics if called from a collection hook (see `GcScope::set_before_mark_hook`).
+  ///
+  /// With the `debug` feature, the location of the caller is recorded: see
+  /// `GcScope::site_histogram`.
+  #[cfg_attr(feature = "debug", track_caller)]
   pub fn alloc<T: Trace + 'gc>(&'gc self, value: T) -> Result<Gc<'gc, T>, GcAllocErr> {
-    unsafe { value.unroot() }
-    self.state.borrow_mut()
-      .alloc(value)
-      .map(|ptr| Gc::new(ptr))
+    assert!(!unsafe { self.marker.as_ref() }.locked.get(), "cannot allocate from a collection hook");
+    match self.state.try_borrow_mut() {
+      Ok(mut state) => state.alloc(value).map(Gc::new),
+      Err(_) => Ok(Gc::new(GcState::alloc_pending(self.marker, value))),
+    }
   }
 
+  /// Collects all the unreachable values of this scope.
+  ///
+  /// If an incremental collection is in progress, it is finished first. This does nothing if the
+  /// scope is already collecting garbage (when called from a destructor or a finalizer).
   pub fn collect_garbage(&self) {
-    self.state.borrow_mut().collect_garbage()
+    if let Ok(mut state) = self.state.try_borrow_mut() {
+      state.collect_garbage()
+    }
+  }
+
+  /// Does a bounded amount of incremental collection work and returns whether the collection is
+  /// complete.
+  ///
+  /// `budget` is the number of boxes to count, check, trace, sweep or drop. A new collection is started if
+  /// none is in progress. The program can keep running between two steps: values stored or moved
+  /// during a collection survive it. This does nothing and returns `false` if the scope is already
+  /// collecting garbage.
+  pub fn collect_step(&self, budget: usize) -> bool {
+    match self.state.try_borrow_mut() {
+      Ok(mut state) => state.collect_step(budget),
+      Err(_) => false,
+    }
+  }
+
+  /// Runs incremental collection steps until the collection is complete or `deadline` is reached,
+  /// and returns whether the collection is complete.
+  ///
+  /// This is intended to collect garbage during idle time. At least one step is run.
+  pub fn collect_until(&self, deadline: Instant) -> bool {
+    loop {
+      match self.state.try_borrow_mut() {
+        Ok(mut state) => if state.collect_step(INCREMENTAL_STEP_BUDGET) {
+          return true;
+        },
+        Err(_) => return false,
+      }
+      if Instant::now() >= deadline {
+        return false;
+      }
+    }
+  }
+
+  /// Enables or disables the generational mode of this scope.
+  ///
+  /// In generational mode, new values are allocated in a nursery. Automatic collections are
+  /// minor collections: they only free the unreachable values of the nursery and promote the
+  /// surviving values to the old generation, so their cost depends on the size of the nursery.
+  /// A full collection follows once the old generation doubled in size since the last one.
+  ///
+  /// Minor collections only count the references between young values: the young values
+  /// referenced by old values are found as roots, from their reference counts. There is no
+  /// remembered set (`GcRefCell::borrow_mut` doesn't record the old values it mutates), so the
+  /// mutations cost nothing more. However, each minor collection still checks all the weak
+  /// references, weak map entries and finalization registrations of the scope, young or old: its
+  /// cost also grows with their number.
+  pub fn set_generational(&self, generational: bool) {
+    self.state.borrow_mut().set_generational(generational)
+  }
+
+  /// Collects the unreachable values of the nursery and promotes the surviving ones.
+  ///
+  /// This does nothing if the scope is not generational, or if an incremental collection is in
+  /// progress (values are not allocated in the nursery during incremental collections).
+  pub fn collect_minor(&self) {
+    if let Ok(mut state) = self.state.try_borrow_mut() {
+      state.collect_minor()
+    }
+  }
+
+  /// Collects garbage if the policy of the scope asks for it, and returns whether it did.
+  ///
+  /// This is a cheap safepoint: call it regularly when the program is in a state where collecting
+  /// is convenient (for example between two instructions of an interpreter). This does nothing and
+  /// returns `false` if the scope is already collecting garbage.
+  pub fn maybe_collect(&self) -> bool {
+    match self.state.try_borrow_mut() {
+      Ok(mut state) => state.maybe_collect(),
+      Err(_) => false,
+    }
+  }
+
+  /// Returns the number of bytes currently allocated in this scope.
+  pub fn allocated_bytes(&self) -> usize {
+    self.state.borrow().allocated_bytes
+  }
+
+  /// Returns the statistics of this scope: its live values, its allocations and its collections.
+  pub fn stats(&self) -> GcStats {
+    self.state.borrow().stats()
+  }
+
+  /// Calls `f` with each live value of this scope.
+  ///
+  /// The values found unreachable by an incremental collection in progress are skipped. The scope
+  /// is borrowed during the walk: `f` can't collect garbage, and the values it allocates are only
+  /// added to the scope by the next collection. This does nothing while the scope is collecting
+  /// garbage or being dropped.
+  pub fn for_each_live<F: FnMut(&dyn Trace)>(&self, mut f: F) {
+    if let Ok(state) = self.state.try_borrow() {
+      state.for_each_live_box(|gc_box_ptr| f(unsafe { &*gc_box_ptr.as_ref().value }));
+    }
+  }
+
+  /// Calls `f` with each live value of this scope and the location of the code allocating it (with
+  /// the `debug` feature), like `for_each_live`.
+  #[cfg(feature = "debug")]
+  pub fn for_each_live_with_site<F: FnMut(&dyn Trace, &'static Location<'static>)>(&self, mut f: F) {
+    if let Ok(state) = self.state.try_borrow() {
+      state.for_each_live_box(|gc_box_ptr| {
+        let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+        f(&*gc_box.value, gc_box.location)
+      });
+    }
+  }
+
+  /// Returns a `Gc` pointer to each live value of type `T` in this scope.
+  ///
+  /// This walks all the values of the scope when called: the values allocated afterwards are not
+  /// returned. The pointers keep the values alive, like the pointers returned by
+  /// `GcWeak::upgrade`. Nothing is returned while the scope is collecting garbage or being
+  /// dropped.
+  ///
+  /// # Safety
+  ///
+  /// The types of the values are compared without their lifetimes: all the values whose type
+  /// differs from `T` only by its lifetimes must be valid for the lifetimes of `T` (for example,
+  /// if `T` is `Node<'gc>`, there must be no value of type `Node<'static>` in the scope). This can't
+  /// be checked: bounding `T` by `'static` would not help, since a `Node<'gc>` value would still be
+  /// returned as a `Node<'static>`. The contract holds if all the values of the type use the
+  /// lifetimes of `T`.
+  pub unsafe fn iter_of<T: Trace + 'gc>(&'gc self) -> impl Iterator<Item = Gc<'gc, T>> {
+    let type_id: TypeId = erased_type_id::<T>();
+    let mut gcs: Vec<Gc<'gc, T>> = Vec::new();
+    let state = match self.state.try_borrow() {
+      Ok(state) => state,
+      Err(_) => return gcs.into_iter(),
+    };
+    state.for_each_live_box(|gc_box_ptr| {
+      let gc_box: &GcBox<dyn Trace> = gc_box_ptr.as_ref();
+      if (gc_box.type_id)() == type_id {
+        let gc_box: &GcBox<T> = gc_box_ptr.cast::<GcBox<'gc, T>>().as_ref();
+        gc_box.inc_refs();
+        // Barrier: during an incremental collection, the box may not be marked yet while its roots
+        // were already checked.
+        gc_box.mark_box();
+        gcs.push(Gc::new(gc_box_ptr.cast::<GcBox<'gc, T>>()));
+      }
+    });
+    gcs.into_iter()
+  }
+
+  /// Returns the allocation statistics of each type allocated in this scope, sorted by decreasing
+  /// live bytes.
+  ///
+  /// This walks all the values of the scope. The types without live values are included if they
+  /// were allocated before.
+  pub fn type_histogram(&self) -> Vec<GcTypeStats> {
+    self.state.borrow().type_histogram()
+  }
+
+  /// Returns the statistics of the live values of this scope for each type and location where
+  /// they were allocated, sorted by decreasing live bytes.
+  ///
+  /// Call it after `collect_garbage` to find where the values that unexpectedly survive were
+  /// allocated. This walks all the values of the scope.
+  #[cfg(feature = "debug")]
+  pub fn site_histogram(&self) -> Vec<GcSiteStats> {
+    self.state.borrow().site_histogram()
+  }
+
+  /// Samples the allocations of this scope roughly every `interval` bytes, to export the live heap
+  /// with `heap_profile` (with the `heap-profiler` feature).
+  ///
+  /// The stack of each sampled allocation is captured, which is slow: use an interval much larger
+  /// than the typical allocation (for example 512 KiB). `None` (the default) stops sampling; the
+  /// samples of the values still alive are kept.
+  #[cfg(feature = "heap-profiler")]
+  pub fn set_heap_sampling_interval(&self, interval: Option<usize>) {
+    self.state.borrow_mut().heap_profiler.set_interval(interval);
+  }
+
+  /// Returns the sampled live heap of this scope, encoded in the pprof format (`profile.proto`),
+  /// with the `heap-profiler` feature.
+  ///
+  /// Each sample is labeled with the type of the value, and weighted to estimate the number of
+  /// objects and bytes allocated at its stack. The profile can be read by `go tool pprof`.
+  #[cfg(feature = "heap-profiler")]
+  pub fn heap_profile(&self) -> Vec<u8> {
+    self.state.borrow().heap_profiler.to_pprof()
+  }
+
+  /// Returns the number of bytes of external memory reported to this scope.
+  pub fn external_bytes(&self) -> usize {
+    unsafe { self.marker.as_ref() }.external_bytes.get()
+  }
+
+  /// Reports `bytes` of memory allocated outside of the scope but owned by its values, for example
+  /// the buffer of a `Vec`.
+  ///
+  /// The external memory counts towards the collection thresholds and the byte limit of the scope:
+  /// this may collect garbage, and the next allocations fail once the external memory fills the
+  /// limit. Report the memory as freed with `report_external_free`, typically from the destructor
+  /// of the value owning it.
+  pub fn report_external_alloc(&self, bytes: usize) {
+    let external_bytes: &Cell<usize> = &unsafe { self.marker.as_ref() }.external_bytes;
+    external_bytes.set(external_bytes.get().saturating_add(bytes));
+    if let Ok(mut state) = self.state.try_borrow_mut() {
+      if !state.arena {
+        state.maybe_collect();
+      }
+    }
+  }
+
+  /// Reports that `bytes` of external memory reported with `report_external_alloc` were freed.
+  ///
+  /// This can be called while the scope collects garbage.
+  pub fn report_external_free(&self, bytes: usize) {
+    let external_bytes: &Cell<usize> = &unsafe { self.marker.as_ref() }.external_bytes;
+    external_bytes.set(external_bytes.get().saturating_sub(bytes));
+  }
+
+  /// Returns the maximum number of bytes that can be allocated in this scope, if any.
+  pub fn byte_limit(&self) -> Option<usize> {
+    self.state.borrow().byte_limit
+  }
+
+  /// Sets the maximum number of bytes that can be allocated in this scope, including the reported
+  /// external memory.
+  ///
+  /// Once an allocation would exceed the limit, garbage is collected: if this does not free
+  /// enough memory, the allocation fails with `GcAllocErr::Exhausted`. `None` (the default)
+  /// removes the limit. Values already allocated are kept even if they exceed a new limit.
+  pub fn set_byte_limit(&self, limit: Option<usize>) {
+    self.state.borrow_mut().byte_limit = limit;
+  }
+
+  /// Sets a callback called once the allocated bytes reach `share` of the byte limit of the scope.
+  ///
+  /// The callback is called during the allocation crossing this share, with the heap of the scope
+  /// and its current limit. It decides how the allocation proceeds: it can raise the limit, release
+  /// values and ask for a collection, or fail the allocation. It is called again once the allocated
+  /// bytes cross the share of a new limit, or after failing an allocation. The callback can't
+  /// collect garbage by itself, and the values it allocates are not limited.
+  ///
+  /// `share` is between `0.0` and `1.0`. The callback is never called if the scope has no limit.
+  /// It may own `Gc` pointers to the values of the scope, for example a cache to release: it is
+  /// dropped before them.
+  pub fn set_near_limit_callback<F>(&self, share: f64, callback: F)
+    where F: FnMut(&GcHeapInfo, usize) -> GcNearLimitAction + 'gc {
+    let mut state = self.state.borrow_mut();
+    state.near_limit_share = share;
+    state.hooks.near_limit = Some(Box::new(callback));
+  }
+
+  /// Registers `held` in the finalization registry of this scope, to be queued once `target` is
+  /// collected.
+  ///
+  /// Like the `FinalizationRegistry` of JavaScript, this lets you release resources associated
+  /// with a value without implementing `Drop` or `Finalize`. The held value must not point to the
+  /// target: it is owned by the scope, not by a managed value.
+  pub fn register_finalizer<T: Trace + 'gc, H: Any>(&self, target: &Gc<'gc, T>, held: H) {
+    let alive: Rc<Cell<bool>> = unsafe { Gc::box_ptr(target).as_ref() }.weak_flag();
+    self.state.borrow_mut().finalization_registry.register(alive, Box::new(held));
+  }
+
+  /// Returns the held values whose target was collected since the last call, in the order the
+  /// targets were found unreachable.
+  pub fn take_finalized(&self) -> Vec<Box<dyn Any>> {
+    self.state.borrow_mut().finalization_registry.take_finalized()
+  }
+
+  /// Sets a debug hook called with each value resurrected by a finalizer.
+  ///
+  /// A finalizer resurrects a value if it stores a `Gc` pointer to an unreachable value in a
+  /// reachable one (or outside of the managed memory). Once all the finalizers of a collection ran,
+  /// the values reachable again from such pointers are not dropped: they are kept alive and
+  /// reported to this hook. The hook must not use the scope.
+  ///
+  /// Destructors can't resurrect values, since cloning a `Gc` pointer panics while values are
+  /// dropped. A pointer moved out of a dropped value keeps its box allocated until it is dropped,
+  /// but the value is dropped too: it is not reported, and dereferencing the pointer panics.
+  pub fn set_resurrection_hook<F: FnMut(&dyn Trace) + 'gc>(&self, hook: F) {
+    self.state.borrow_mut().hooks.resurrection = Some(Box::new(hook));
+  }
+
+  /// Sets a hook called with the statistics of the scope when a collection starts looking for the
+  /// reachable values.
+  ///
+  /// The collection hooks let you observe the collections, for example to profile them. They run
+  /// while the scope is locked: they can't allocate in the scope (`alloc` panics), and the
+  /// statistics they get are read-only. The durations of the statistics include the current
+  /// collection. The hooks may borrow the scope and own `Gc` pointers to its values.
+  pub fn set_before_mark_hook<F: FnMut(&GcStats) + 'gc>(&self, hook: F) {
+    self.state.borrow_mut().hooks.before_mark = Some(Box::new(hook));
+  }
+
+  /// Sets a hook called with the statistics of the scope once a collection marked all the
+  /// reachable values.
+  ///
+  /// See `GcScope::set_before_mark_hook` for the restrictions of the collection hooks.
+  pub fn set_after_mark_hook<F: FnMut(&GcStats) + 'gc>(&self, hook: F) {
+    self.state.borrow_mut().hooks.after_mark = Some(Box::new(hook));
+  }
+
+  /// Sets a hook called with the statistics of the scope before a collection finalizes and drops
+  /// the unreachable values.
+  ///
+  /// See `GcScope::set_before_mark_hook` for the restrictions of the collection hooks.
+  pub fn set_before_sweep_hook<F: FnMut(&GcStats) + 'gc>(&self, hook: F) {
+    self.state.borrow_mut().hooks.before_sweep = Some(Box::new(hook));
+  }
+
+  /// Sets a hook called with the statistics of the scope once a collection is complete.
+  ///
+  /// See `GcScope::set_before_mark_hook` for the restrictions of the collection hooks.
+  pub fn set_after_collection_hook<F: FnMut(&GcStats) + 'gc>(&self, hook: F) {
+    self.state.borrow_mut().hooks.after_collection = Some(Box::new(hook));
+  }
+
+  /// Sets a hook called with the type name and the size (in bytes) of each value freed by a
+  /// collection.
+  ///
+  /// The hook is called once the value is dropped and its memory released. See
+  /// `GcScope::set_before_mark_hook` for the restrictions of the collection hooks.
+  pub fn set_free_hook<F: FnMut(&'static str, usize) + 'gc>(&self, hook: F) {
+    self.state.borrow_mut().hooks.free = Some(Box::new(hook));
+  }
+}
+
+impl<'gc> Default for GcScope<'gc> {
+  fn default() -> GcScope<'gc> {
+    GcScope::new()
+  }
+}
+
+// The state stays borrowed while the values are dropped: the finalizers and destructors can't
+// collect garbage or walk the heap, and the values they allocate are dropped too
+unsafe impl<#[may_dangle] 'gc> Drop for GcScope<'gc> {
+  fn drop(&mut self) {
+    self.state.borrow_mut().drop_all_boxes();
   }
 }
 
 #[derive(Debug)]
-struct GcState<'gc> {
+pub(crate) struct GcState<'gc> {
   pub(crate) allocated_bytes: usize,
-  //  threshold: usize,
-  // Linked-list of boxes
+  pub(crate) allocated_objects: usize,
+  pub(crate) bytes_since_collection: usize,
+  // External bytes at the end of the last collection
+  pub(crate) external_bytes_at_collection: usize,
+  pub(crate) allocations_since_collection: usize,
+  // Decides when to collect garbage automatically
+  pub(crate) policy: Box<dyn CollectionPolicy>,
+  // Maximum number of allocated bytes
+  pub(crate) byte_limit: Option<usize>,
+  // Share of the byte limit triggering the near-limit callback
+  pub(crate) near_limit_share: f64,
+  // Limit for which the near-limit callback was called, until the allocated bytes go below its share
+  pub(crate) near_limit_notified: Option<usize>,
+  // Phase and gray worklist, shared with the boxes
+  pub(crate) marker: NonNull<GcMarker<'gc>>,
+  // Next box to visit during the "count" and "roots" phases
+  pub(crate) cursor: Option<NonNull<GcBox<'gc, dyn Trace>>>,
+  // Linked-list of the boxes remaining to check during the "sweep" phase
+  pub(crate) sweeping: Option<NonNull<GcBox<'gc, dyn Trace>>>,
+  // Linked-list of the unmarked boxes whose value was finalized, dropped during the "drop" phase
+  pub(crate) dead: Option<NonNull<GcBox<'gc, dyn Trace>>>,
+  // Linked-list of the boxes whose value was dropped, freed at the end of the "drop" phase
+  pub(crate) dropped: Option<NonNull<GcBox<'gc, dyn Trace>>>,
+  // Linked-list of the boxes whose value was dropped while a destructor moved a `Gc` pointer to them
+  // out of the dropped values, freed once the pointers are dropped
+  pub(crate) escaped: Option<NonNull<GcBox<'gc, dyn Trace>>>,
+  // Boxes freed so far by the current collection
+  pub(crate) freed_objects: usize,
+  pub(crate) freed_bytes: usize,
+  // Time spent so far by the current collection in each half, and start of the unaccounted time
+  pub(crate) mark_duration: Duration,
+  pub(crate) sweep_duration: Duration,
+  pub(crate) clock: Instant,
+  // `tracing` span of the current phase, exited at the end of each collection step
+  pub(crate) phase_span: GcPhaseSpan,
+  // Whether new boxes are allocated in the nursery
+  pub(crate) generational: bool,
+  // Linked-list of the young boxes
+  pub(crate) nursery: Option<NonNull<GcBox<'gc, dyn Trace>>>,
+  pub(crate) nursery_bytes: usize,
+  pub(crate) nursery_objects: usize,
+  // Number of old bytes triggering a major collection after a minor one
+  pub(crate) major_threshold: usize,
+  // Linked-list of boxes (old generation)
   pub(crate) boxes: Option<NonNull<GcBox<'gc, dyn Trace>>>,
+  // Whether the boxes are owned by a `GcArena`: they are pointed to by uncounted `ArenaGc`
+  // pointers, so the roots come from the arena and collections only run between mutations
+  pub(crate) arena: bool,
+  // Held values registered with `GcScope::register_finalizer`
+  pub(crate) finalization_registry: FinalizationRegistry,
+  // Callbacks of the scope, dropped by `drop_all_boxes` before the boxes they may point to: like
+  // the boxes, they may own `Gc` pointers without outliving `'gc`
+  pub(crate) hooks: ManuallyDrop<GcHooks<'gc>>,
+  // Totals since the creation of the scope
+  pub(crate) stats: GcStats,
+  // Number of values allocated for each type name
+  pub(crate) type_allocations: HashMap<&'static str, usize>,
+  #[cfg(feature = "heap-profiler")]
+  pub(crate) heap_profiler: HeapProfiler,
 }
 
 impl<'gc> GcState<'gc> {
-  pub(crate) fn new() -> GcState<'gc> {
+  pub(crate) fn new(policy: Box<dyn CollectionPolicy>) -> GcState<'gc> {
     GcState {
       allocated_bytes: 0,
+      allocated_objects: 0,
+      bytes_since_collection: 0,
+      external_bytes_at_collection: 0,
+      allocations_since_collection: 0,
+      policy,
+      byte_limit: None,
+      near_limit_share: 1.0,
+      near_limit_notified: None,
+      marker: unsafe { NonNull::new_unchecked(Box::into_raw(Box::new(GcMarker::new()))) },
+      cursor: None,
+      sweeping: None,
+      dead: None,
+      dropped: None,
+      escaped: None,
+      freed_objects: 0,
+      freed_bytes: 0,
+      mark_duration: Duration::default(),
+      sweep_duration: Duration::default(),
+      clock: Instant::now(),
+      phase_span: GcPhaseSpan::default(),
+      generational: false,
+      nursery: None,
+      nursery_bytes: 0,
+      nursery_objects: 0,
+      major_threshold: MIN_MAJOR_THRESHOLD,
       boxes: None,
+      arena: false,
+      finalization_registry: FinalizationRegistry::default(),
+      hooks: ManuallyDrop::new(GcHooks::default()),
+      stats: GcStats::default(),
+      type_allocations: HashMap::new(),
+      #[cfg(feature = "heap-profiler")]
+      heap_profiler: HeapProfiler::default(),
+    }
+  }
+
+  fn marker(&self) -> &GcMarker<'gc> {
+    unsafe { self.marker.as_ref() }
+  }
+
+  // Number of bytes allocated in the scope, including the external memory
+  fn heap_bytes(&self) -> usize {
+    self.allocated_bytes.saturating_add(self.marker().external_bytes.get())
+  }
+
+  // Statistics of the scope, including the durations of the current collection
+  pub(crate) fn stats(&self) -> GcStats {
+    GcStats {
+      live_objects: self.allocated_objects,
+      live_bytes: self.allocated_bytes,
+      mark_duration: self.stats.mark_duration + self.mark_duration,
+      sweep_duration: self.stats.sweep_duration + self.sweep_duration,
+      ..self.stats
+    }
+  }
+
+  pub(crate) fn type_histogram(&self) -> Vec<GcTypeStats> {
+    let mut histogram: HashMap<&'static str, GcTypeStats> = HashMap::new();
+    for (&type_name, &total_allocations) in self.type_allocations.iter() {
+      histogram.insert(type_name, GcTypeStats { type_name, live_objects: 0, live_bytes: 0, total_allocations });
+    }
+    self.for_each_box(|gc_box| {
+      // Every box is counted by `record_allocation`
+      let type_stats: &mut GcTypeStats = histogram.get_mut(gc_box.type_name).unwrap();
+      type_stats.live_objects += 1;
+      type_stats.live_bytes += gc_box.size.get();
+    });
+    let mut histogram: Vec<GcTypeStats> = histogram.into_values().collect();
+    histogram.sort_by(|a, b| b.live_bytes.cmp(&a.live_bytes).then(a.type_name.cmp(b.type_name)));
+    histogram
+  }
+
+  #[cfg(feature = "debug")]
+  pub(crate) fn site_histogram(&self) -> Vec<GcSiteStats> {
+    let mut histogram: HashMap<(&'static str, &'static Location<'static>), GcSiteStats> = HashMap::new();
+    self.for_each_box(|gc_box| {
+      let site_stats: &mut GcSiteStats = histogram.entry((gc_box.type_name, gc_box.location)).or_insert(GcSiteStats {
+        type_name: gc_box.type_name,
+        location: gc_box.location,
+        live_objects: 0,
+        live_bytes: 0,
+      });
+      site_stats.live_objects += 1;
+      site_stats.live_bytes += gc_box.size.get();
+    });
+    let mut histogram: Vec<GcSiteStats> = histogram.into_values().collect();
+    histogram.sort_by(|a, b| {
+      b.live_bytes.cmp(&a.live_bytes).then(a.type_name.cmp(b.type_name)).then(a.location.cmp(b.location))
+    });
+    histogram
+  }
+
+  // Visits the live boxes: during the "sweep" phase, the unmarked boxes not swept yet and the dead
+  // boxes are skipped
+  fn for_each_live_box<F: FnMut(NonNull<GcBox<'gc, dyn Trace>>)>(&self, mut f: F) {
+    for &(list, only_marked) in [(self.boxes, false), (self.nursery, false), (self.sweeping, true)].iter() {
+      let mut next_gc_box_ptr = list;
+      while let Some(gc_box_ptr) = next_gc_box_ptr {
+        let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+        if !only_marked || gc_box.marked.get() {
+          f(gc_box_ptr);
+        }
+        next_gc_box_ptr = gc_box.next;
+      }
+    }
+  }
+
+  // Visits the boxes counted as allocated: the live boxes, and during a collection the boxes not
+  // swept yet and the dead boxes not dropped yet
+  fn for_each_box<F: FnMut(&GcBox<'gc, dyn Trace>)>(&self, mut f: F) {
+    for list in [self.boxes, self.nursery, self.sweeping, self.dead].iter() {
+      let mut next_gc_box_ptr = *list;
+      while let Some(gc_box_ptr) = next_gc_box_ptr {
+        let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+        f(gc_box);
+        next_gc_box_ptr = gc_box.next;
+      }
     }
   }
 
+  // Calls a collection hook while the scope is locked: allocating from the hook panics
+  fn run_hook<F: FnOnce(&mut GcHooks<'gc>)>(&mut self, f: F) {
+    self.marker().locked.set(true);
+    f(&mut self.hooks);
+    self.marker().locked.set(false);
+  }
+
+  // Calls a collection hook with the current statistics
+  fn run_stats_hook(&mut self, hook: fn(&mut GcHooks<'gc>, &GcStats)) {
+    let stats: GcStats = self.stats();
+    self.run_hook(|hooks| hook(hooks, &stats));
+  }
+
+  pub(crate) fn heap_info(&self) -> GcHeapInfo {
+    let external_bytes: usize = self.marker().external_bytes.get();
+    let external_growth: usize = external_bytes.saturating_sub(self.external_bytes_at_collection);
+    GcHeapInfo {
+      allocated_bytes: self.allocated_bytes,
+      allocated_objects: self.allocated_objects,
+      external_bytes,
+      bytes_since_collection: self.bytes_since_collection.saturating_add(external_growth),
+      allocations_since_collection: self.allocations_since_collection,
+      nursery_bytes: self.nursery_bytes,
+      nursery_objects: self.nursery_objects,
+    }
+  }
+
+  // Collects garbage if the policy asks for it
+  pub(crate) fn maybe_collect(&mut self) -> bool {
+    let heap_info: GcHeapInfo = self.heap_info();
+    if !self.policy.should_collect(&heap_info) {
+      return false;
+    }
+    if self.generational && self.marker().phase() == GcPhase::Idle {
+      self.collect_minor();
+      if self.heap_bytes() >= self.major_threshold {
+        self.collect_garbage();
+      }
+    } else {
+      self.collect_garbage();
+    }
+    true
+  }
+
+  pub(crate) fn set_generational(&mut self, generational: bool) {
+    if !generational {
+      self.promote_nursery();
+    }
+    self.generational = generational;
+  }
+
   // Allocates GC-managed memory for T
+  #[cfg_attr(feature = "debug", track_caller)]
   pub(crate) fn alloc<T: Trace + 'gc>(&mut self, value: T) -> Result<NonNull<GcBox<'gc, T>>, GcAllocErr> {
+    let size: usize = size_of::<GcBox<T>>() + value.heap_size();
+    #[cfg(feature = "tracing")]
+    {
+      if size >= LARGE_ALLOCATION_BYTES {
+        ::tracing::debug!(bytes = size, type_name = ::std::any::type_name::<T>(), "large allocation");
+      }
+    }
+    // `value` is not managed yet: the values it points to are rooted during this collection.
+    if !self.arena {
+      self.maybe_collect();
+    }
+    if !self.check_near_limit(size) {
+      return Err(GcAllocErr::Exhausted);
+    }
+    if !self.fits_in_limit(size) {
+      // Garbage is collected only once the limit is reached: it may free enough memory
+      if !self.arena {
+        self.collect_garbage();
+      }
+      if !self.fits_in_limit(size) {
+        #[cfg(feature = "tracing")]
+        ::tracing::warn!(
+          bytes = size,
+          heap_bytes = self.heap_bytes(),
+          objects = self.allocated_objects,
+          limit = self.byte_limit,
+          "allocation exceeds the byte limit",
+        );
+        return Err(GcAllocErr::Exhausted);
+      }
+    }
+    #[cfg(feature = "heap-profiler")]
+    let sampled: bool = self.heap_profiler.should_sample(size);
+    // The nursery is only used between collections
+    let young: bool = self.generational && self.marker().phase() == GcPhase::Idle;
     // into_raw -> mem::forget, so we need to make sure we deallocate it ourselve
     let gc_box_ptr: *mut GcBox<T> = Box::into_raw(Box::new(GcBox {
-      roots: Cell::new(1),
-      marked: Cell::new(false),
-      next: self.boxes,
-      value: value,
+      refs: Cell::new(if self.arena { 0 } else { 1 }),
+      internal_refs: Cell::new(0),
+      // Boxes allocated while marking are considered reachable until the next collection
+      marked: Cell::new(self.marker().is_marking()),
+      young: Cell::new(young),
+      finalized: Cell::new(false),
+      value_dropped: Cell::new(false),
+      size: Cell::new(size),
+      type_name: ::std::any::type_name::<T>(),
+      type_id: erased_type_id::<T>,
+      #[cfg(feature = "debug")]
+      location: Location::caller(),
+      #[cfg(feature = "heap-profiler")]
+      sampled: Cell::new(sampled),
+      weak: RefCell::new(None),
+      marker: self.marker,
+      next: if young { self.nursery } else { self.boxes },
+      value: ManuallyDrop::new(value),
     }));
-    self.allocated_bytes += size_of::<GcBox<T>>();
+    self.allocated_bytes += size;
+    self.allocated_objects += 1;
+    self.bytes_since_collection += size;
+    self.allocations_since_collection += 1;
+    self.record_allocation(::std::any::type_name::<T>(), size);
+    #[cfg(feature = "heap-profiler")]
+    {
+      if sampled {
+        self.heap_profiler.sample(gc_box_ptr as usize, ::std::any::type_name::<T>(), size);
+      }
+    }
     // We know that `gc_box` is not null so we can use `new_unchecked`
     let box_ptr: NonNull<GcBox<T>> = unsafe { NonNull::new_unchecked(gc_box_ptr) };
-    self.boxes = Some(box_ptr);
+    if young {
+      self.nursery_bytes += size;
+      self.nursery_objects += 1;
+      self.nursery = Some(box_ptr);
+    } else {
+      self.boxes = Some(box_ptr);
+    }
     Ok(unsafe { NonNull::new_unchecked(gc_box_ptr) })
   }
 
+  // Calls the near-limit callback if allocating `size` more bytes reaches its share of the limit for
+  // the first time, and returns `false` if the callback fails the allocation
+  fn check_near_limit(&mut self, size: usize) -> bool {
+    let limit: usize = match self.byte_limit {
+      Some(limit) => limit,
+      None => return true,
+    };
+    let bytes: usize = self.heap_bytes().saturating_add(size);
+    if (bytes as f64) < (limit as f64) * self.near_limit_share {
+      self.near_limit_notified = None;
+      return true;
+    }
+    if self.near_limit_notified == Some(limit) || self.hooks.near_limit.is_none() {
+      return true;
+    }
+    self.near_limit_notified = Some(limit);
+    let heap_info: GcHeapInfo = self.heap_info();
+    let action: GcNearLimitAction = self.hooks.on_near_limit(&heap_info, limit);
+    #[cfg(feature = "tracing")]
+    ::tracing::info!(
+      heap_bytes = heap_info.allocated_bytes + heap_info.external_bytes,
+      objects = heap_info.allocated_objects,
+      limit,
+      action = ?action,
+      "near the byte limit",
+    );
+    match action {
+      GcNearLimitAction::Continue => true,
+      GcNearLimitAction::Collect => {
+        if !self.arena {
+          self.collect_garbage();
+        }
+        true
+      }
+      GcNearLimitAction::SetLimit(limit) => {
+        self.byte_limit = Some(limit);
+        true
+      }
+      GcNearLimitAction::Fail => {
+        self.near_limit_notified = None;
+        false
+      }
+    }
+  }
+
+  // Returns `true` if `size` more bytes can be allocated without exceeding the byte limit
+  fn fits_in_limit(&self, size: usize) -> bool {
+    match self.byte_limit {
+      Some(limit) => self.heap_bytes().saturating_add(size) <= limit,
+      None => true,
+    }
+  }
+
+  // Allocates a box while the state is borrowed by a collection: it is added to the pending boxes
+  // of the marker, without updating the counters of the state.
+  #[cfg_attr(feature = "debug", track_caller)]
+  pub(crate) fn alloc_pending<T: Trace + 'gc>(marker: NonNull<GcMarker<'gc>>, value: T) -> NonNull<GcBox<'gc, T>> {
+    let marker_ref: &GcMarker<'gc> = unsafe { marker.as_ref() };
+    let gc_box_ptr: *mut GcBox<T> = Box::into_raw(Box::new(GcBox {
+      refs: Cell::new(1),
+      internal_refs: Cell::new(0),
+      marked: Cell::new(false),
+      young: Cell::new(false),
+      finalized: Cell::new(false),
+      value_dropped: Cell::new(false),
+      // Measured once the box is added to the scope
+      size: Cell::new(0),
+      type_name: ::std::any::type_name::<T>(),
+      type_id: erased_type_id::<T>,
+      #[cfg(feature = "debug")]
+      location: Location::caller(),
+      #[cfg(feature = "heap-profiler")]
+      sampled: Cell::new(false),
+      weak: RefCell::new(None),
+      marker,
+      next: marker_ref.pending.get(),
+      value: ManuallyDrop::new(value),
+    }));
+    let box_ptr: NonNull<GcBox<T>> = unsafe { NonNull::new_unchecked(gc_box_ptr) };
+    marker_ref.pending.set(Some(box_ptr));
+    box_ptr
+  }
+
+  // Adds the boxes allocated during the last collection work to the scope
+  fn adopt_pending_boxes(&mut self) {
+    let mut next_gc_box_ptr = self.marker().pending.take();
+    while let Some(gc_box_ptr) = next_gc_box_ptr {
+      let gc_box_ptr: *mut GcBox<dyn Trace> = gc_box_ptr.as_ptr();
+      unsafe {
+        next_gc_box_ptr = (*gc_box_ptr).next;
+        // Like in `alloc`, boxes allocated while marking are considered reachable
+        (*gc_box_ptr).marked.set(self.marker().is_marking());
+        (*gc_box_ptr).next = self.boxes;
+        self.boxes = Some(NonNull::new_unchecked(gc_box_ptr));
+        self.allocated_objects += 1;
+        self.allocations_since_collection += 1;
+        let size: usize = measure_box(&*gc_box_ptr);
+        (*gc_box_ptr).size.set(size);
+        self.allocated_bytes += size;
+        self.bytes_since_collection += size;
+        self.record_allocation((*gc_box_ptr).type_name, size);
+      }
+    }
+  }
+
+  fn record_allocation(&mut self, type_name: &'static str, size: usize) {
+    *self.type_allocations.entry(type_name).or_insert(0) += 1;
+    self.stats.total_allocations += 1;
+    self.stats.total_allocated_bytes += size;
+    self.stats.peak_bytes = ::std::cmp::max(self.stats.peak_bytes, self.allocated_bytes);
+  }
+
+  // Finishes the current collection, if any, then runs a full collection
   pub(crate) fn collect_garbage(&mut self) {
-    {
-      // Mark
-      let mut next_gc_box_ptr = self.boxes;
+    if self.marker().phase() != GcPhase::Idle {
+      self.collect_step(usize::MAX);
+    }
+    self.collect_step(usize::MAX);
+  }
+
+  // Runs a full collection where the only roots are the boxes reached from `root`: the reference
+  // counts are ignored.
+  pub(crate) fn collect_garbage_from(&mut self, root: &dyn Trace) {
+    if self.marker().phase() != GcPhase::Idle {
+      self.complete_collection_from(root);
+    }
+    self.promote_nursery();
+    // There is no box to check during the "roots" phase
+    self.cursor = None;
+    self.marker().set_phase(GcPhase::Roots);
+    self.unwind_safe(|state| {
+      state.phase_span.enter(GcPhase::Roots, false);
+      state.run_stats_hook(GcHooks::on_before_mark);
+      unsafe { root.mark() }
+    });
+    self.phase_span.exit();
+    self.complete_collection_from(root);
+  }
+
+  // Completes the collection in progress of an arena. The sweep stops before the "resurrect"
+  // phase: arena boxes have no reference count, so the dead boxes stored by finalizers are found by
+  // tracing `root` again.
+  fn complete_collection_from(&mut self, root: &dyn Trace) {
+    if !self.collect_step(usize::MAX) {
+      self.unwind_safe(|state| {
+        state.phase_span.enter(GcPhase::Resurrect, false);
+        state.resurrect_dead_boxes(Some(root))
+      });
+      self.phase_span.exit();
+      self.collect_step(usize::MAX);
+    }
+  }
+
+  // Collects garbage from `root` if the policy asks for it
+  pub(crate) fn maybe_collect_from(&mut self, root: &dyn Trace) -> bool {
+    let heap_info: GcHeapInfo = self.heap_info();
+    if !self.policy.should_collect(&heap_info) {
+      return false;
+    }
+    self.collect_garbage_from(root);
+    true
+  }
+
+  // Does up to `budget` units of work (boxes counted, checked, traced, swept or dropped) on the current
+  // collection, starting a new one if needed. Returns `true` if the collection is complete.
+  pub(crate) fn collect_step(&mut self, budget: usize) -> bool {
+    let complete: bool = self.unwind_safe(|state| state.run_collect_step(budget));
+    self.phase_span.exit();
+    self.adopt_pending_boxes();
+    complete
+  }
+
+  // Runs some collection work, and restores a consistent state before propagating the panics of
+  // user code (`Trace::mark`, `Finalize::finalize` or `Drop`)
+  fn unwind_safe<R, F: FnOnce(&mut GcState<'gc>) -> R>(&mut self, f: F) -> R {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+      Ok(result) => result,
+      Err(payload) => {
+        self.recover_from_panic();
+        self.adopt_pending_boxes();
+        panic::resume_unwind(payload)
+      }
+    }
+  }
+
+  // Restores a consistent state after a panic during a collection. The boxes are always linked in
+  // one of the lists of the state before user code runs on them.
+  fn recover_from_panic(&mut self) {
+    self.marker().counting.set(false);
+    self.marker().dropping.set(false);
+    self.marker().locked.set(false);
+    self.phase_span.exit();
+    match self.marker().phase() {
+      GcPhase::Count | GcPhase::Roots | GcPhase::Mark => self.abort_marking(),
+      GcPhase::Resurrect => self.abort_resurrection(),
+      // The box being finalized or dropped is already in the list of dead or dropped boxes: the
+      // next collection step resumes the sweep
+      GcPhase::Idle | GcPhase::Sweep | GcPhase::Drop => {}
+    }
+  }
+
+  // Aborts the search for reachable boxes: the marks and internal counts are cleared, so the next
+  // collection starts from scratch
+  fn abort_marking(&mut self) {
+    self.marker().gray.borrow_mut().clear();
+    for table in mem::take(&mut *self.marker().ephemerons.borrow_mut()).iter() {
+      table.unregister();
+    }
+    for list in [self.boxes, self.nursery].iter() {
+      let mut next_gc_box_ptr = *list;
       while let Some(gc_box_ptr) = next_gc_box_ptr {
         let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
-        if gc_box.roots.get() > 0 {
-          gc_box.mark_box();
-        }
+        gc_box.marked.set(false);
+        gc_box.internal_refs.set(0);
         next_gc_box_ptr = gc_box.next;
       }
     }
+    self.cursor = None;
+    self.marker().minor.set(false);
+    self.marker().set_phase(GcPhase::Idle);
+  }
 
-    let mut unmarked: Vec<*mut GcBox<dyn Trace>> = Vec::new();
+  // Aborts the search for resurrected boxes: all the dead boxes are kept alive, as if they were
+  // resurrected
+  fn abort_resurrection(&mut self) {
+    self.marker().gray.borrow_mut().clear();
+    let mut next_gc_box_ptr = self.dead.take();
+    while let Some(gc_box_ptr) = next_gc_box_ptr {
+      let gc_box_ptr: *mut GcBox<dyn Trace> = gc_box_ptr.as_ptr();
+      unsafe {
+        next_gc_box_ptr = (*gc_box_ptr).next;
+        (*gc_box_ptr).marked.set(false);
+        (*gc_box_ptr).internal_refs.set(0);
+        (*gc_box_ptr).finalized.set(false);
+        (*gc_box_ptr).young.set(false);
+        (*gc_box_ptr).next = self.boxes;
+        self.boxes = Some(NonNull::new_unchecked(gc_box_ptr));
+      }
+    }
+    self.marker().set_phase(GcPhase::Drop);
+  }
+
+  fn run_collect_step(&mut self, budget: usize) -> bool {
+    self.clock = Instant::now();
+    if self.marker().phase() == GcPhase::Idle {
+      // Full collections consider all the boxes as old
+      self.promote_nursery();
+      self.cursor = self.boxes;
+      self.marker().set_phase(GcPhase::Count);
+      self.run_stats_hook(GcHooks::on_before_mark);
+    }
+
+    let mut work: usize = 0;
+    while work < budget {
+      self.phase_span.enter(self.marker().phase(), self.marker().minor.get());
+      match self.marker().phase() {
+        GcPhase::Count => {
+          match self.cursor {
+            Some(gc_box_ptr) => {
+              self.count_box(gc_box_ptr);
+              self.cursor = unsafe { gc_box_ptr.as_ref().next };
+              work += 1;
+            }
+            None => {
+              // The boxes allocated since the start of the collection are checked too: they are
+              // already marked.
+              self.cursor = self.boxes;
+              self.marker().set_phase(GcPhase::Roots);
+            }
+          }
+        }
+        GcPhase::Roots => {
+          match self.cursor {
+            Some(gc_box_ptr) => {
+              let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+              if gc_box.refs.get() > gc_box.internal_refs.get() {
+                self.marker().shade(gc_box_ptr);
+              }
+              self.cursor = gc_box.next;
+              work += 1;
+            }
+            None => self.marker().set_phase(GcPhase::Mark),
+          }
+        }
+        GcPhase::Mark => {
+          match self.marker().pop_gray() {
+            Some(gc_box_ptr) => {
+              unsafe { gc_box_ptr.as_ref().value.mark() }
+              work += 1;
+            }
+            None => {
+              // The gray worklist is empty: every reachable box is marked, unless the values of
+              // weak maps were reached through their key.
+              if !self.trace_ephemerons() {
+                self.sweeping = self.boxes.take();
+                self.start_sweep();
+              }
+            }
+          }
+        }
+        GcPhase::Sweep => {
+          match self.sweeping {
+            Some(gc_box_ptr) => {
+              self.sweeping = unsafe { gc_box_ptr.as_ref().next };
+              if let Some(gc_box_ptr) = self.sweep_box(gc_box_ptr) {
+                unsafe { (*gc_box_ptr.as_ptr()).next = self.boxes; }
+                self.boxes = Some(gc_box_ptr);
+                self.update_box_size(gc_box_ptr);
+              }
+              work += 1;
+            }
+            None if self.arena => {
+              self.marker().set_phase(GcPhase::Resurrect);
+              break;
+            }
+            None => self.resurrect_dead_boxes(None),
+          }
+        }
+        GcPhase::Drop => {
+          match self.dead {
+            Some(gc_box_ptr) => {
+              self.drop_box(gc_box_ptr);
+              work += 1;
+            }
+            None => {
+              self.free_dropped_boxes();
+              self.record_duration();
+              let kind: GcCollectionKind = if self.marker().minor.get() {
+                GcCollectionKind::Minor
+              } else {
+                let major_threshold: usize = ::std::cmp::max(MIN_MAJOR_THRESHOLD, self.heap_bytes().saturating_mul(2));
+                #[cfg(feature = "tracing")]
+                {
+                  if self.generational && major_threshold != self.major_threshold {
+                    ::tracing::debug!(old = self.major_threshold, new = major_threshold, "major collection threshold changed");
+                  }
+                }
+                self.major_threshold = major_threshold;
+                GcCollectionKind::Major
+              };
+              self.finish_collection(kind);
+              self.run_stats_hook(GcHooks::on_after_collection);
+              return true;
+            }
+          }
+        }
+        GcPhase::Idle | GcPhase::Resurrect => unreachable!(),
+      }
+    }
+    self.record_duration();
+    false
+  }
+
+  // Adds the time elapsed since the last call to the current half of the collection
+  fn record_duration(&mut self) {
+    let now: Instant = Instant::now();
+    let elapsed: Duration = now.duration_since(self.clock);
+    if self.marker().is_marking() {
+      self.mark_duration += elapsed;
+    } else {
+      self.sweep_duration += elapsed;
+    }
+    self.clock = now;
+  }
+
+  // Traces the values of the weak maps reached during this collection whose key is reached, and
+  // returns whether it shaded new boxes. Marking is complete once this reaches a fixpoint.
+  fn trace_ephemerons(&mut self) -> bool {
+    // The tables are cloned: tracing the values may reach new weak maps
+    let tables: Vec<Rc<dyn Ephemerons<'gc> + 'gc>> = self.marker().ephemerons.borrow().clone();
+    for table in tables.iter() {
+      table.trace_reached_values();
+    }
+    !self.marker().gray.borrow().is_empty()
+  }
+
+  // Ends the "mark" phase: clears the weak references to the unmarked boxes, queues the held values
+  // of their finalization registrations and removes the entries of the weak maps whose key is
+  // unmarked, before any unmarked box is dropped. The weak maps that were not reached, for example
+  // on the stack, are pruned too.
+  fn start_sweep(&mut self) {
+    self.record_duration();
+    self.run_stats_hook(GcHooks::on_after_mark);
+    // Minor collections check the old weak references, weak map entries and registrations too
+    self.clear_weak_refs();
+    self.finalization_registry.queue_collected();
+    self.marker().set_phase(GcPhase::Sweep);
+    let tables: Vec<Rc<dyn Ephemerons<'gc> + 'gc>> = mem::take(&mut *self.marker().ephemerons.borrow_mut());
+    for table in tables.iter() {
+      table.unregister();
+    }
+    let tables: Vec<Rc<dyn Ephemerons<'gc> + 'gc>> = {
+      let mut weak_maps = self.marker().weak_maps.borrow_mut();
+      weak_maps.retain(|table| table.strong_count() > 0);
+      weak_maps.iter().filter_map(Weak::upgrade).collect()
+    };
+    // Dropping the values of the removed entries may panic
+    for table in tables.iter() {
+      table.remove_dead_entries();
+    }
+    self.run_stats_hook(GcHooks::on_before_sweep);
+  }
+
+  // Clears the weak references to the unmarked boxes, before any value is dropped: `GcWeak::upgrade`
+  // can't reach a dead box, even from a destructor
+  fn clear_weak_refs(&mut self) {
+    let minor: bool = self.marker().minor.get();
+    self.marker().weak_boxes.borrow_mut().retain(|gc_box_ptr| {
+      let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+      if gc_box.marked.get() || (minor && !gc_box.young.get()) {
+        return true;
+      }
+      if let Some(alive) = gc_box.weak.borrow_mut().take() {
+        alive.set(false);
+      }
+      false
+    });
+  }
+
+  // Clears the weak references to the dead boxes created by finalizers, before these boxes were
+  // finalized themselves
+  fn clear_dead_weak_refs(&mut self) {
+    self.marker().weak_boxes.borrow_mut().retain(|gc_box_ptr| {
+      let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+      if !gc_box.finalized.get() {
+        return true;
+      }
+      if let Some(alive) = gc_box.weak.borrow_mut().take() {
+        alive.set(false);
+      }
+      false
+    });
+  }
+
+  // Finalizes, drops and frees all the boxes, then the boxes allocated meanwhile by the finalizers
+  // and destructors, until there is none left
+  fn drop_all_boxes(&mut self) {
+    self.marker().set_phase(GcPhase::Idle);
+    // The hooks and the weak maps of the current collection may own values pointing to the boxes
+    drop(mem::take(&mut *self.hooks));
+    drop(mem::take(&mut *self.marker().ephemerons.borrow_mut()));
+    loop {
+      self.adopt_pending_boxes();
+      // The `Gc` pointers to the escaped boxes can't outlive the scope
+      let lists: [Option<NonNull<GcBox<'gc, dyn Trace>>>; 6] = [
+        self.boxes.take(),
+        self.sweeping.take(),
+        self.nursery.take(),
+        self.dead.take(),
+        self.dropped.take(),
+        self.escaped.take(),
+      ];
+      if lists.iter().all(Option::is_none) {
+        return;
+      }
+      for gc_box_ptr in self.marker().weak_boxes.borrow_mut().drain(..) {
+        if let Some(alive) = unsafe { gc_box_ptr.as_ref() }.weak.borrow_mut().take() {
+          alive.set(false);
+        }
+      }
+      // All the values are finalized, then dropped, before any box is freed
+      for list in lists[..3].iter() {
+        let mut cur_box = *list;
+        while let Some(gc_box_ptr) = cur_box {
+          let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+          gc_box.finalized.set(true);
+          gc_box.value.finalize();
+          cur_box = gc_box.next;
+        }
+      }
+      for list in lists[..4].iter() {
+        let mut cur_box = *list;
+        while let Some(gc_box_ptr) = cur_box {
+          unsafe {
+            cur_box = gc_box_ptr.as_ref().next;
+            drop_value(gc_box_ptr.as_ptr());
+          }
+        }
+      }
+      for list in lists.iter() {
+        let mut cur_box = *list;
+        while let Some(gc_box_ptr) = cur_box {
+          let gc_box = unsafe { Box::from_raw(gc_box_ptr.as_ptr()) };
+          cur_box = gc_box.next;
+          // Implicitly drops `gc_box` and frees the associated memory
+        }
+      }
+    }
+  }
+
+  // Counts the references from the value of this box to other boxes
+  fn count_box(&self, gc_box_ptr: NonNull<GcBox<'gc, dyn Trace>>) {
+    self.marker().counting.set(true);
+    unsafe { gc_box_ptr.as_ref().value.mark() }
+    self.marker().counting.set(false);
+  }
+
+  // Resets a marked box and returns it, or moves an unmarked box to the list of dead boxes and
+  // finalizes its value
+  fn sweep_box(&mut self, gc_box_ptr: NonNull<GcBox<'gc, dyn Trace>>) -> Option<NonNull<GcBox<'gc, dyn Trace>>> {
+    let gc_box_ptr: *mut GcBox<dyn Trace> = gc_box_ptr.as_ptr();
     unsafe {
-      // Collect
-      let mut next_gc_box_ptr_ref = &mut self.boxes;
-      while let Some(gc_box_ptr) = *next_gc_box_ptr_ref {
-        let gc_box_ptr = gc_box_ptr.as_ptr();
+      if (*gc_box_ptr).marked.get() {
+        (*gc_box_ptr).marked.set(false);
+        (*gc_box_ptr).internal_refs.set(0);
+        // The surviving boxes of a minor collection are promoted
+        (*gc_box_ptr).young.set(false);
+        Some(NonNull::new_unchecked(gc_box_ptr))
+      } else {
+        (*gc_box_ptr).finalized.set(true);
+        (*gc_box_ptr).next = self.dead;
+        self.dead = Some(NonNull::new_unchecked(gc_box_ptr));
+        (*gc_box_ptr).value.finalize();
+        None
+      }
+    }
+  }
+
+  // Moves the dead boxes reachable again back to the live boxes, once all the finalizers ran.
+  //
+  // The references between dead boxes are counted: the other references to dead boxes were created
+  // by finalizers. The dead boxes with such references are marked, along with the dead boxes they
+  // reach. This pass is not incremental: the mutator can't run while it's in progress.
+  //
+  // Arena boxes have no reference count: the root of the arena and the surviving boxes are traced
+  // instead, to mark the dead boxes they point to.
+  fn resurrect_dead_boxes(&mut self, root: Option<&dyn Trace>) {
+    if self.dead.is_none() {
+      self.marker().set_phase(GcPhase::Drop);
+      return;
+    }
+    self.marker().set_phase(GcPhase::Resurrect);
+    match root {
+      Some(root) => {
+        unsafe { root.mark() }
+        let mut next_gc_box_ptr = self.boxes;
+        while let Some(gc_box_ptr) = next_gc_box_ptr {
+          let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+          unsafe { gc_box.value.mark() }
+          next_gc_box_ptr = gc_box.next;
+        }
+      }
+      None => {
+        let mut next_gc_box_ptr = self.dead;
+        while let Some(gc_box_ptr) = next_gc_box_ptr {
+          let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+          gc_box.internal_refs.set(0);
+          next_gc_box_ptr = gc_box.next;
+        }
+        let mut next_gc_box_ptr = self.dead;
+        while let Some(gc_box_ptr) = next_gc_box_ptr {
+          self.count_box(gc_box_ptr);
+          next_gc_box_ptr = unsafe { gc_box_ptr.as_ref().next };
+        }
+        let mut next_gc_box_ptr = self.dead;
+        while let Some(gc_box_ptr) = next_gc_box_ptr {
+          let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+          if gc_box.refs.get() > gc_box.internal_refs.get() {
+            self.marker().shade(gc_box_ptr);
+          }
+          next_gc_box_ptr = gc_box.next;
+        }
+      }
+    }
+    while let Some(gc_box_ptr) = self.marker().pop_gray() {
+      unsafe { gc_box_ptr.as_ref().value.mark() }
+    }
+
+    let mut resurrected: Vec<NonNull<GcBox<'gc, dyn Trace>>> = Vec::new();
+    let mut next_gc_box_ptr = self.dead.take();
+    while let Some(gc_box_ptr) = next_gc_box_ptr {
+      let gc_box_ptr: *mut GcBox<dyn Trace> = gc_box_ptr.as_ptr();
+      unsafe {
+        next_gc_box_ptr = (*gc_box_ptr).next;
         if (*gc_box_ptr).marked.get() {
           (*gc_box_ptr).marked.set(false);
-          next_gc_box_ptr_ref = &mut (*gc_box_ptr).next;
+          (*gc_box_ptr).internal_refs.set(0);
+          (*gc_box_ptr).finalized.set(false);
+          // Resurrected boxes are old, even during a minor collection
+          (*gc_box_ptr).young.set(false);
+          (*gc_box_ptr).next = self.boxes;
+          self.boxes = Some(NonNull::new_unchecked(gc_box_ptr));
+          resurrected.push(NonNull::new_unchecked(gc_box_ptr));
         } else {
-          *next_gc_box_ptr_ref = (*gc_box_ptr).next;
-          unmarked.push(gc_box_ptr);
+          (*gc_box_ptr).next = self.dead;
+          self.dead = Some(NonNull::new_unchecked(gc_box_ptr));
+        }
+      }
+    }
+    self.clear_dead_weak_refs();
+    self.marker().set_phase(GcPhase::Drop);
+    for gc_box_ptr in resurrected {
+      self.hooks.on_resurrection(unsafe { &*gc_box_ptr.as_ref().value });
+    }
+  }
+
+  // Measures again a surviving box: its value may have acquired or released heap memory
+  fn update_box_size(&mut self, gc_box_ptr: NonNull<GcBox<'gc, dyn Trace>>) {
+    let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+    let size: usize = measure_box(gc_box);
+    self.allocated_bytes = self.allocated_bytes - gc_box.size.get() + size;
+    gc_box.size.set(size);
+    self.stats.peak_bytes = ::std::cmp::max(self.stats.peak_bytes, self.allocated_bytes);
+  }
+
+  // Drops the value of the first dead box, and moves the box to the list of dropped boxes. The box
+  // is not freed yet: the other dead values may still release their references to it.
+  fn drop_box(&mut self, gc_box_ptr: NonNull<GcBox<'gc, dyn Trace>>) {
+    let gc_box_ptr: *mut GcBox<dyn Trace> = gc_box_ptr.as_ptr();
+    unsafe {
+      self.dead = (*gc_box_ptr).next;
+      let size: usize = (*gc_box_ptr).size.get();
+      self.allocated_bytes = self.allocated_bytes.checked_sub(size).unwrap();
+      self.allocated_objects -= 1;
+      self.freed_objects += 1;
+      self.freed_bytes += size;
+      (*gc_box_ptr).next = self.dropped;
+      self.dropped = Some(NonNull::new_unchecked(gc_box_ptr));
+      drop_value(gc_box_ptr);
+    }
+  }
+
+  // Frees the dropped boxes, once all the dead values are dropped, and the escaped boxes whose
+  // `Gc` pointers were dropped since the last collection.
+  //
+  // A box still counting `Gc` pointers once all the dead values are dropped is reachable again: a
+  // destructor moved a pointer to it out of its value. The box is kept as escaped until these
+  // pointers are dropped, since its value is already dropped.
+  fn free_dropped_boxes(&mut self) {
+    let mut next_gc_box_ptr = self.escaped.take();
+    while let Some(gc_box_ptr) = next_gc_box_ptr {
+      let gc_box_ptr: *mut GcBox<dyn Trace> = gc_box_ptr.as_ptr();
+      unsafe {
+        next_gc_box_ptr = (*gc_box_ptr).next;
+        (*gc_box_ptr).next = self.dropped;
+        self.dropped = Some(NonNull::new_unchecked(gc_box_ptr));
+      }
+    }
+    while let Some(gc_box_ptr) = self.dropped {
+      self.dropped = unsafe { gc_box_ptr.as_ref().next };
+      if unsafe { gc_box_ptr.as_ref() }.refs.get() > 0 {
+        unsafe { (*gc_box_ptr.as_ptr()).next = self.escaped; }
+        self.escaped = Some(gc_box_ptr);
+        continue;
+      }
+      let gc_box = unsafe { Box::from_raw(gc_box_ptr.as_ptr()) };
+      #[cfg(feature = "heap-profiler")]
+      {
+        if gc_box.sampled.get() {
+          self.heap_profiler.remove(gc_box_ptr.as_ptr() as *const u8 as usize);
         }
       }
+      let type_name: &'static str = gc_box.type_name;
+      let size: usize = gc_box.size.get();
+      // Frees the memory of the box before reporting it
+      mem::drop(gc_box);
+      self.run_hook(|hooks| hooks.on_free(type_name, size));
     }
+  }
+
+  // Moves the young boxes to the old generation
+  fn promote_nursery(&mut self) {
+    let mut next_gc_box_ptr = self.nursery.take();
+    while let Some(gc_box_ptr) = next_gc_box_ptr {
+      let gc_box_ptr: *mut GcBox<dyn Trace> = gc_box_ptr.as_ptr();
+      unsafe {
+        next_gc_box_ptr = (*gc_box_ptr).next;
+        (*gc_box_ptr).young.set(false);
+        (*gc_box_ptr).next = self.boxes;
+        self.boxes = Some(NonNull::new_unchecked(gc_box_ptr));
+      }
+    }
+    self.nursery_bytes = 0;
+    self.nursery_objects = 0;
+  }
 
-    for gc_box_ptr in unmarked.iter() {
-      let gc_box = unsafe { Box::from_raw(*gc_box_ptr) };
-      self.allocated_bytes = self.allocated_bytes.checked_sub(size_of_val::<GcBox<_>>(gc_box.as_ref())).unwrap()
-      // Implicitly drops `gc_box` and frees the associated memory
+  // Collects the young boxes: only the references between young boxes are counted, so the young
+  // boxes referenced by old boxes are roots, and the "mark" signal is not propagated through old
+  // boxes.
+  pub(crate) fn collect_minor(&mut self) {
+    if !self.generational || self.marker().phase() != GcPhase::Idle {
+      return;
+    }
+    self.unwind_safe(|state| state.run_collect_minor());
+    self.phase_span.exit();
+    self.adopt_pending_boxes();
+  }
+
+  fn run_collect_minor(&mut self) {
+    self.clock = Instant::now();
+    self.marker().minor.set(true);
+    self.marker().set_phase(GcPhase::Count);
+    self.phase_span.enter(GcPhase::Count, true);
+    self.run_stats_hook(GcHooks::on_before_mark);
+    let mut next_gc_box_ptr = self.nursery;
+    while let Some(gc_box_ptr) = next_gc_box_ptr {
+      self.count_box(gc_box_ptr);
+      next_gc_box_ptr = unsafe { gc_box_ptr.as_ref().next };
+    }
+
+    self.marker().set_phase(GcPhase::Roots);
+    let mut next_gc_box_ptr = self.nursery;
+    while let Some(gc_box_ptr) = next_gc_box_ptr {
+      let gc_box: &GcBox<dyn Trace> = unsafe { gc_box_ptr.as_ref() };
+      if gc_box.refs.get() > gc_box.internal_refs.get() {
+        self.marker().shade(gc_box_ptr);
+      }
+      next_gc_box_ptr = gc_box.next;
+    }
+
+    self.marker().set_phase(GcPhase::Mark);
+    loop {
+      while let Some(gc_box_ptr) = self.marker().pop_gray() {
+        unsafe { gc_box_ptr.as_ref().value.mark() }
+      }
+      if !self.trace_ephemerons() {
+        break;
+      }
+    }
+
+    // The nursery is swept like the old generation by a full collection: the surviving boxes are
+    // promoted
+    self.sweeping = self.nursery.take();
+    self.nursery_bytes = 0;
+    self.nursery_objects = 0;
+    self.start_sweep();
+    self.run_collect_step(usize::MAX);
+  }
+
+  fn finish_collection(&mut self, kind: GcCollectionKind) {
+    self.marker().minor.set(false);
+    self.marker().set_phase(GcPhase::Idle);
+    let result: GcCollectionResult = GcCollectionResult {
+      kind,
+      freed_objects: self.freed_objects,
+      freed_bytes: self.freed_bytes,
+      live_objects: self.allocated_objects,
+      live_bytes: self.allocated_bytes,
+      external_bytes: self.marker().external_bytes.get(),
+      mark_duration: self.mark_duration,
+      sweep_duration: self.sweep_duration,
+    };
+    self.external_bytes_at_collection = result.external_bytes;
+    self.freed_objects = 0;
+    self.freed_bytes = 0;
+    self.mark_duration = Duration::default();
+    self.sweep_duration = Duration::default();
+    self.stats.record_collection(&result);
+    self.bytes_since_collection = 0;
+    self.allocations_since_collection = 0;
+    #[cfg(feature = "tracing")]
+    ::tracing::info!(
+      kind = ?result.kind,
+      freed_objects = result.freed_objects,
+      freed_bytes = result.freed_bytes,
+      live_objects = result.live_objects,
+      live_bytes = result.live_bytes,
+      external_bytes = result.external_bytes,
+      mark_duration_us = result.mark_duration.as_micros() as u64,
+      sweep_duration_us = result.sweep_duration.as_micros() as u64,
+      "garbage collection",
+    );
+    #[cfg(feature = "tracing")]
+    let threshold: Option<usize> = self.policy.threshold();
+    self.policy.on_collect(&result);
+    #[cfg(feature = "tracing")]
+    {
+      let new_threshold: Option<usize> = self.policy.threshold();
+      if new_threshold != threshold {
+        ::tracing::debug!(old = threshold, new = new_threshold, "collection threshold changed");
+      }
     }
   }
 }
 
 unsafe impl<#[may_dangle] 'gc> Drop for GcState<'gc> {
   fn drop(&mut self) {
-    let mut cur_box = self.boxes;
-    while let Some(gc_box_ptr) = cur_box {
-      let gc_box = unsafe { Box::from_raw(gc_box_ptr.as_ptr()) };
-      cur_box = (*gc_box).next;
-      // Implicitly drops `gc_box` and frees the associated memory
-    }
+    self.drop_all_boxes();
+    unsafe { drop(Box::from_raw(self.marker.as_ptr())) }
   }
 }
+
+// Returns the number of bytes to account for a box: its size and the heap memory of its value. The
+// values of other boxes are not included.
+fn measure_box(gc_box: &GcBox<dyn Trace>) -> usize {
+  size_of_val(gc_box) + gc_box.value.heap_size()
+}
+
+// Drops the value of a box, rejecting the dereferences of `Gc` pointers meanwhile: the value may
+// point to boxes whose value is already dropped.
+unsafe fn drop_value<'gc>(gc_box_ptr: *mut GcBox<'gc, dyn Trace>) {
+  let marker: &GcMarker<'gc> = (*gc_box_ptr).marker.as_ref();
+  marker.dropping.set(true);
+  (*gc_box_ptr).value_dropped.set(true);
+  ManuallyDrop::drop(&mut (*gc_box_ptr).value);
+  marker.dropping.set(false);
+}
@@ -0,0 +1,19 @@
+use ::std::panic::Location;
+
+/// Statistics of the live values allocated at a location of the code, returned by
+/// `GcScope::site_histogram` (with the `debug` feature).
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct GcSiteStats {
+  /// Name of the type of the values.
+  pub type_name: &'static str,
+
+  /// Location of the call to `GcScope::alloc` (or `MutationContext::alloc`) allocating the
+  /// values.
+  pub location: &'static Location<'static>,
+
+  /// Number of values allocated at this location currently alive in the scope.
+  pub live_objects: usize,
+
+  /// Number of bytes currently allocated for these values.
+  pub live_bytes: usize,
+}
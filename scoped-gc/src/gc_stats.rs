@@ -0,0 +1,61 @@
+use ::std::time::Duration;
+use collection_policy::{GcCollectionKind, GcCollectionResult};
+
+/// Statistics of a `GcScope`, returned by `GcScope::stats`.
+///
+/// The totals are counted since the creation of the scope. With the `serde` feature, the
+/// statistics can be serialized (durations are serialized as seconds and nanoseconds).
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GcStats {
+  /// Number of values currently allocated in the scope.
+  pub live_objects: usize,
+
+  /// Number of bytes currently allocated in the scope.
+  pub live_bytes: usize,
+
+  /// Maximum number of bytes allocated in the scope at the same time.
+  pub peak_bytes: usize,
+
+  /// Total number of values allocated.
+  pub total_allocations: usize,
+
+  /// Total number of bytes allocated.
+  pub total_allocated_bytes: usize,
+
+  /// Number of completed collections, minor and major.
+  pub collections: usize,
+
+  /// Number of completed minor collections.
+  pub minor_collections: usize,
+
+  /// Total number of values freed by the collections.
+  pub freed_objects: usize,
+
+  /// Total number of bytes freed by the collections.
+  pub freed_bytes: usize,
+
+  /// Total time spent looking for the reachable values.
+  pub mark_duration: Duration,
+
+  /// Total time spent finalizing, dropping and freeing the unreachable values.
+  pub sweep_duration: Duration,
+
+  /// Result of the last completed collection, with the values and bytes it freed.
+  pub last_collection: Option<GcCollectionResult>,
+}
+
+impl GcStats {
+  // Adds a completed collection to the totals
+  pub(crate) fn record_collection(&mut self, result: &GcCollectionResult) {
+    self.collections += 1;
+    if result.kind == GcCollectionKind::Minor {
+      self.minor_collections += 1;
+    }
+    self.freed_objects += result.freed_objects;
+    self.freed_bytes += result.freed_bytes;
+    self.mark_duration += result.mark_duration;
+    self.sweep_duration += result.sweep_duration;
+    self.last_collection = Some(*result);
+  }
+}
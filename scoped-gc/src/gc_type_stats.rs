@@ -0,0 +1,19 @@
+/// Allocation statistics of the values of a type, returned by `GcScope::type_histogram`.
+///
+/// Types are identified by their name (`::std::any::type_name`): it is only meant for diagnostics.
+/// With the `serde` feature, these statistics can be serialized.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct GcTypeStats {
+  /// Name of the type.
+  pub type_name: &'static str,
+
+  /// Number of values of this type currently allocated in the scope.
+  pub live_objects: usize,
+
+  /// Number of bytes currently allocated for the values of this type.
+  pub live_bytes: usize,
+
+  /// Total number of values of this type allocated.
+  pub total_allocations: usize,
+}
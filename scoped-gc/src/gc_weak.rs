@@ -0,0 +1,63 @@
+use ::std::cell::Cell;
+use ::std::ptr::NonNull;
+use ::std::rc::Rc;
+use finalize::Finalize;
+use gc::Gc;
+use gc_box::GcBox;
+use heap_size::HeapSize;
+use trace::Trace;
+
+/// A weak pointer to a value managed by a garbage-collector.
+///
+/// It is created with `Gc::downgrade` and does not keep its value alive: once the value is found
+/// unreachable by a collection, `upgrade` returns `None`. The weak pointers are cleared before any
+/// unreachable value is dropped or freed.
+#[derive(Debug)]
+pub struct GcWeak<'gc, T: Trace + 'gc> {
+  ptr: NonNull<GcBox<'gc, T>>,
+  // Shared with the box, cleared when the box is found unreachable
+  alive: Rc<Cell<bool>>,
+}
+
+impl<'gc, T: Trace + 'gc> GcWeak<'gc, T> {
+  pub(crate) fn new(ptr: NonNull<GcBox<'gc, T>>, alive: Rc<Cell<bool>>) -> GcWeak<'gc, T> {
+    GcWeak { ptr, alive }
+  }
+
+  pub(crate) fn box_ptr(&self) -> NonNull<GcBox<'gc, T>> {
+    self.ptr
+  }
+
+  /// Returns `true` if the value was not collected.
+  pub(crate) fn is_alive(&self) -> bool {
+    self.alive.get()
+  }
+
+  /// Returns a `Gc` pointer to the value, or `None` if it was collected.
+  pub fn upgrade(&self) -> Option<Gc<'gc, T>> {
+    if !self.alive.get() {
+      return None;
+    }
+    let gc_box: &GcBox<'gc, T> = unsafe { self.ptr.as_ref() };
+    gc_box.inc_refs();
+    // Barrier: during an incremental collection, the box may not be marked yet while its roots
+    // were already checked.
+    gc_box.mark_box();
+    Some(Gc::new(self.ptr))
+  }
+}
+
+impl<'gc, T: Trace> Finalize for GcWeak<'gc, T> {}
+
+impl<'gc, T: Trace> HeapSize for GcWeak<'gc, T> {}
+
+unsafe impl<'gc, T: Trace> Trace for GcWeak<'gc, T> {
+  /// Does nothing: weak pointers do not keep their value alive.
+  unsafe fn mark(&self) {}
+}
+
+impl<'gc, T: Trace + 'gc> Clone for GcWeak<'gc, T> {
+  fn clone(&self) -> GcWeak<'gc, T> {
+    GcWeak { ptr: self.ptr, alive: Rc::clone(&self.alive) }
+  }
+}
@@ -0,0 +1,213 @@
+use ::std::cell::{Cell, Ref, RefCell};
+use ::std::collections::HashMap;
+use ::std::fmt::{self, Debug};
+use ::std::mem::size_of;
+use ::std::ptr::NonNull;
+use ::std::rc::Rc;
+use finalize::Finalize;
+use gc::Gc;
+use gc_box::GcBox;
+use gc_marker::{is_collection_active, GcMarker, GcPhase};
+use gc_weak::GcWeak;
+use heap_size::HeapSize;
+use trace::Trace;
+
+/// Internal trait for the tables of the weak maps reached during a collection.
+pub(crate) trait Ephemerons<'gc>: Debug {
+  /// Shades the values whose key was reached.
+  fn trace_reached_values(&self);
+
+  /// Forgets that the table was reached by the current collection.
+  fn unregister(&self);
+
+  /// Removes the entries whose key was collected, unless the entries are borrowed.
+  fn remove_dead_entries(&self);
+}
+
+/// Entries of a weak map, indexed by the address of the box of their key.
+type Entries<'gc, K, V> = HashMap<*const GcBox<'gc, K>, (GcWeak<'gc, K>, V)>;
+
+/// Entries of a weak map, shared with the marker while a collection is in progress so they stay
+/// at a stable address.
+struct EphemeronTable<'gc, K: Trace + 'gc, V: Trace + 'gc> {
+  // Marker of the scope of the keys, known once a key is inserted
+  marker: Cell<Option<NonNull<GcMarker<'gc>>>>,
+  // Whether the table was reached during the current collection
+  registered: Cell<bool>,
+  entries: RefCell<Entries<'gc, K, V>>,
+}
+
+impl<'gc, K: Trace + 'gc, V: Trace + 'gc> Debug for EphemeronTable<'gc, K, V> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("EphemeronTable")
+      .field("registered", &self.registered.get())
+      .field("entries", &self.entries.borrow().len())
+      .finish()
+  }
+}
+
+impl<'gc, K: Trace + 'gc, V: Trace + 'gc> Ephemerons<'gc> for EphemeronTable<'gc, K, V> {
+  fn trace_reached_values(&self) {
+    let marker: &GcMarker<'gc> = match self.marker.get() {
+      Some(marker) => unsafe { marker.as_ref() },
+      None => return,
+    };
+    for (key, value) in self.entries.borrow().values() {
+      // The box of a key is only read while it is alive
+      if key.is_alive() && marker.is_reached(unsafe { key.box_ptr().as_ref() }) {
+        unsafe { value.mark() }
+      }
+    }
+  }
+
+  fn unregister(&self) {
+    self.registered.set(false);
+  }
+
+  fn remove_dead_entries(&self) {
+    let dead: Vec<(GcWeak<'gc, K>, V)> = {
+      // The entries whose key is collected are ignored until the next collection
+      let mut entries = match self.entries.try_borrow_mut() {
+        Ok(entries) => entries,
+        Err(_) => return,
+      };
+      let dead_keys: Vec<*const GcBox<'gc, K>> = entries.iter()
+        .filter(|&(_, (key, _))| !key.is_alive())
+        .map(|(&key_ptr, _)| key_ptr)
+        .collect();
+      dead_keys.iter().filter_map(|key_ptr| entries.remove(key_ptr)).collect()
+    };
+    // The values are dropped once the entries are no longer borrowed
+    drop(dead);
+  }
+}
+
+/// A map with weak keys, where each value is reachable only while its key is reachable through
+/// some other path (an ephemeron table).
+///
+/// Keys are compared by identity: two `Gc` pointers are the same key if they point to the same
+/// value. The entries whose key is collected are removed by the collection, and their values are
+/// dropped. The map must only contain keys of a single scope.
+#[derive(Debug)]
+pub struct GcWeakMap<'gc, K: Trace + 'gc, V: Trace + 'gc> {
+  table: Rc<EphemeronTable<'gc, K, V>>,
+}
+
+impl<'gc, K: Trace + 'gc, V: Trace + 'gc> GcWeakMap<'gc, K, V> {
+  pub fn new() -> GcWeakMap<'gc, K, V> {
+    GcWeakMap {
+      table: Rc::new(EphemeronTable {
+        marker: Cell::new(None),
+        registered: Cell::new(false),
+        entries: RefCell::new(HashMap::new()),
+      }),
+    }
+  }
+
+  /// Inserts a value for `key`, and returns the previous value if any.
+  pub fn insert(&mut self, key: &Gc<'gc, K>, value: V) -> Option<V> {
+    let key_ptr: NonNull<GcBox<'gc, K>> = Gc::box_ptr(key);
+    if self.table.marker.get().is_none() {
+      let marker: NonNull<GcMarker<'gc>> = unsafe { key_ptr.as_ref() }.marker;
+      self.table.marker.set(Some(marker));
+      // The scope removes the entries whose key is collected, even if the map is not reached
+      let table: Rc<dyn Ephemerons<'gc> + 'gc> = Rc::clone(&self.table) as Rc<dyn Ephemerons<'gc> + 'gc>;
+      unsafe { marker.as_ref() }.weak_maps.borrow_mut().push(Rc::downgrade(&table));
+    }
+    let old = self.table.entries.borrow_mut().insert(key_ptr.as_ptr(), (Gc::downgrade(key), value));
+    match old {
+      // An entry whose key is collected may remain at the same address if the map was not traced
+      Some((old_key, old_value)) if old_key.is_alive() => Some(barrier(old_value)),
+      _ => None,
+    }
+  }
+
+  /// Returns the value for `key`, if any.
+  pub fn get(&self, key: &Gc<'gc, K>) -> Option<Ref<'_, V>> {
+    let key_ptr: *const GcBox<'gc, K> = Gc::box_ptr(key).as_ptr();
+    Ref::filter_map(self.table.entries.borrow(), |entries| {
+      entries.get(&key_ptr).filter(|(key, _)| key.is_alive()).map(|(_, value)| value)
+    }).ok()
+  }
+
+  /// Returns `true` if the map contains a value for `key`.
+  pub fn contains_key(&self, key: &Gc<'gc, K>) -> bool {
+    self.get(key).is_some()
+  }
+
+  /// Removes the value for `key` and returns it, if any.
+  pub fn remove(&mut self, key: &Gc<'gc, K>) -> Option<V> {
+    let key_ptr: *const GcBox<'gc, K> = Gc::box_ptr(key).as_ptr();
+    let old = self.table.entries.borrow_mut().remove(&key_ptr);
+    match old {
+      Some((old_key, old_value)) if old_key.is_alive() => Some(barrier(old_value)),
+      _ => None,
+    }
+  }
+
+  /// Returns the number of entries whose key was not collected.
+  pub fn len(&self) -> usize {
+    self.table.entries.borrow().values().filter(|(key, _)| key.is_alive()).count()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+/// Write barrier of incremental collections: a value removed from the map while the collection is
+/// in progress survives it, even if its key was not reached yet.
+fn barrier<V: Trace>(value: V) -> V {
+  if is_collection_active() {
+    unsafe { value.mark() }
+  }
+  value
+}
+
+impl<'gc, K: Trace + 'gc, V: Trace + 'gc> Default for GcWeakMap<'gc, K, V> {
+  fn default() -> GcWeakMap<'gc, K, V> {
+    GcWeakMap::new()
+  }
+}
+
+impl<'gc, K: Trace + 'gc, V: Trace + 'gc> Finalize for GcWeakMap<'gc, K, V> {}
+
+/// The table is counted like the one of a `HashMap`.
+impl<'gc, K: Trace + 'gc, V: Trace + 'gc> HeapSize for GcWeakMap<'gc, K, V> {
+  fn heap_size(&self) -> usize {
+    let entries = self.table.entries.borrow();
+    let table_size: usize = entries.capacity() * (size_of::<(*const GcBox<'gc, K>, (GcWeak<'gc, K>, V))>() + 1);
+    size_of::<EphemeronTable<'gc, K, V>>() + table_size + entries.values().map(|(_, value)| value.heap_size()).sum::<usize>()
+  }
+}
+
+unsafe impl<'gc, K: Trace + 'gc, V: Trace + 'gc> Trace for GcWeakMap<'gc, K, V> {
+  /// Visits the values whose key is reachable.
+  ///
+  /// While counting, all the values are visited: they are owned by the map. While marking, the
+  /// map is registered with the collector so the values of the keys reached later are visited too.
+  /// A map resurrected by a finalizer visits the values of the keys that were not collected.
+  unsafe fn mark(&self) {
+    let marker: &GcMarker<'gc> = match self.table.marker.get() {
+      Some(marker) => marker.as_ref(),
+      None => return,
+    };
+    if marker.counting.get() {
+      for (_, value) in self.table.entries.borrow().values() {
+        value.mark()
+      }
+    } else if marker.is_marking() {
+      if !self.table.registered.get() {
+        self.table.registered.set(true);
+        marker.ephemerons.borrow_mut().push(Rc::clone(&self.table) as Rc<dyn Ephemerons<'gc> + 'gc>);
+      }
+      self.table.trace_reached_values();
+    } else if marker.phase() == GcPhase::Resurrect {
+      for (key, value) in self.table.entries.borrow().values() {
+        if key.is_alive() {
+          value.mark()
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,56 @@
+use finalize::Finalize;
+use gc::Gc;
+use gc_weak_map::GcWeakMap;
+use heap_size::HeapSize;
+use trace::Trace;
+
+/// A set with weak elements: the elements are removed once they are collected.
+///
+/// Elements are compared by identity, like the keys of `GcWeakMap`.
+#[derive(Debug, Default)]
+pub struct GcWeakSet<'gc, K: Trace + 'gc> {
+  map: GcWeakMap<'gc, K, ()>,
+}
+
+impl<'gc, K: Trace + 'gc> GcWeakSet<'gc, K> {
+  pub fn new() -> GcWeakSet<'gc, K> {
+    GcWeakSet { map: GcWeakMap::new() }
+  }
+
+  /// Adds `value` to the set, and returns `true` if it was not present.
+  pub fn insert(&mut self, value: &Gc<'gc, K>) -> bool {
+    self.map.insert(value, ()).is_none()
+  }
+
+  pub fn contains(&self, value: &Gc<'gc, K>) -> bool {
+    self.map.contains_key(value)
+  }
+
+  /// Removes `value` from the set, and returns `true` if it was present.
+  pub fn remove(&mut self, value: &Gc<'gc, K>) -> bool {
+    self.map.remove(value).is_some()
+  }
+
+  /// Returns the number of elements that were not collected.
+  pub fn len(&self) -> usize {
+    self.map.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.map.is_empty()
+  }
+}
+
+impl<'gc, K: Trace + 'gc> Finalize for GcWeakSet<'gc, K> {}
+
+impl<'gc, K: Trace + 'gc> HeapSize for GcWeakSet<'gc, K> {
+  fn heap_size(&self) -> usize {
+    self.map.heap_size()
+  }
+}
+
+unsafe impl<'gc, K: Trace + 'gc> Trace for GcWeakSet<'gc, K> {
+  unsafe fn mark(&self) {
+    self.map.mark()
+  }
+}
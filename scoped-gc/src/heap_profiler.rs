@@ -0,0 +1,111 @@
+use ::std::cmp::max;
+use ::std::collections::HashMap;
+use pprof_writer::{PprofLine, PprofWriter};
+
+/// Prefixes of the functions of the profiler and of the allocator, removed from the top of the
+/// sampled stacks.
+const INTERNAL_FRAMES: [&str; 4] = ["backtrace::", "scoped_gc::heap_profiler::", "scoped_gc::gc_scope::", "scoped_gc::gc_arena::"];
+
+/// A sampled allocation, with the instruction pointers of its stack (innermost first).
+#[derive(Debug)]
+struct HeapSample {
+  type_name: &'static str,
+  size: usize,
+  stack: Vec<usize>,
+}
+
+/// Internal struct sampling the allocations of a scope, enabled with
+/// `GcScope::set_heap_sampling_interval`.
+///
+/// An allocation is sampled each time the allocated bytes cross the sampling interval: its stack
+/// is captured, and kept until the box is freed. Each sample stands for the interval, so the
+/// exported profile estimates the whole live heap.
+#[derive(Debug, Default)]
+pub(crate) struct HeapProfiler {
+  interval: Option<usize>,
+  // Number of bytes to allocate before taking the next sample
+  bytes_until_sample: usize,
+  // Samples of the live boxes, by address
+  samples: HashMap<usize, HeapSample>,
+}
+
+impl HeapProfiler {
+  pub(crate) fn set_interval(&mut self, interval: Option<usize>) {
+    self.interval = interval;
+    self.bytes_until_sample = interval.unwrap_or(0);
+  }
+
+  /// Counts an allocation of `size` bytes and returns whether it should be sampled.
+  pub(crate) fn should_sample(&mut self, size: usize) -> bool {
+    let interval: usize = match self.interval {
+      Some(interval) => interval,
+      None => return false,
+    };
+    if size < self.bytes_until_sample {
+      self.bytes_until_sample -= size;
+      false
+    } else {
+      self.bytes_until_sample = interval;
+      true
+    }
+  }
+
+  /// Captures the stack of the allocation of the box at `address`.
+  pub(crate) fn sample(&mut self, address: usize, type_name: &'static str, size: usize) {
+    let mut stack: Vec<usize> = Vec::new();
+    ::backtrace::trace(|frame| {
+      stack.push(frame.ip() as usize);
+      true
+    });
+    self.samples.insert(address, HeapSample { type_name, size, stack });
+  }
+
+  /// Forgets the sample of a freed box.
+  pub(crate) fn remove(&mut self, address: usize) {
+    self.samples.remove(&address);
+  }
+
+  /// Encodes the samples of the live boxes as a pprof profile.
+  pub(crate) fn to_pprof(&self) -> Vec<u8> {
+    let interval: usize = max(self.interval.unwrap_or(1), 1);
+    let mut writer: PprofWriter = PprofWriter::new();
+    let mut lines_cache: HashMap<usize, Vec<PprofLine>> = HashMap::new();
+    for sample in self.samples.values() {
+      let mut location_ids: Vec<u64> = Vec::new();
+      for &ip in sample.stack.iter() {
+        let lines: &Vec<PprofLine> = lines_cache.entry(ip).or_insert_with(|| resolve(ip));
+        // Skips the frames of the profiler and of the allocator, up to the caller of `alloc`
+        if location_ids.is_empty() && lines.iter().all(|line| is_internal(&line.0)) {
+          continue;
+        }
+        location_ids.push(writer.location_id(ip, lines));
+      }
+      // A sample stands for the allocations of the interval, or only itself if it is larger
+      let objects: usize = max(interval / max(sample.size, 1), 1);
+      writer.add_sample(&location_ids, sample.type_name, objects as u64, (objects * sample.size) as u64);
+    }
+    writer.finish(interval)
+  }
+}
+
+// Returns the source lines of an instruction pointer, innermost first
+fn resolve(ip: usize) -> Vec<PprofLine> {
+  let mut lines: Vec<PprofLine> = Vec::new();
+  ::backtrace::resolve(ip as *mut _, |symbol| {
+    let name: String = match symbol.name() {
+      Some(name) => format!("{:#}", name),
+      None => String::from("<unknown>"),
+    };
+    let file: String = match symbol.filename() {
+      Some(file) => file.display().to_string(),
+      None => String::new(),
+    };
+    lines.push((name, file, symbol.lineno().unwrap_or(0)));
+  });
+  lines
+}
+
+fn is_internal(function: &str) -> bool {
+  let function: &str = function.trim_start_matches('<');
+  INTERNAL_FRAMES.iter().any(|prefix| function.starts_with(prefix))
+}
@@ -0,0 +1,92 @@
+use ::std::collections::{BTreeMap, HashMap};
+use ::std::hash::Hash;
+use ::std::mem::{size_of, size_of_val};
+use ::std::ops::Range;
+use ::std::path::PathBuf;
+
+/// Measures the heap memory owned by a value, to account for it in the allocated bytes of its
+/// scope.
+///
+/// The heap memory of a value is the memory it owns outside of its own size: the buffer of a
+/// `String`, the capacity of a `Vec` (and the heap memory of its items), the table of a `HashMap`,
+/// etc. The values managed by other boxes are not owned: the heap size of a `Gc` pointer is 0, so
+/// their memory is only counted once.
+///
+/// The default implementation returns 0: this is correct for the types that don't own heap memory.
+/// You can derive this trait with `#[derive(HeapSize)]`.
+///
+/// The heap size of a value is measured when it is allocated, then again each time it survives a
+/// collection: the memory it acquires in-between is accounted for by the next collection.
+pub trait HeapSize {
+  /// Returns the number of bytes of heap memory owned by this value.
+  fn heap_size(&self) -> usize {
+    0
+  }
+}
+
+impl HeapSize for String {
+  fn heap_size(&self) -> usize {
+    self.capacity()
+  }
+}
+
+impl HeapSize for PathBuf {
+  fn heap_size(&self) -> usize {
+    self.capacity()
+  }
+}
+
+impl<T: HeapSize> HeapSize for Range<T> {
+  fn heap_size(&self) -> usize {
+    self.start.heap_size() + self.end.heap_size()
+  }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+  fn heap_size(&self) -> usize {
+    size_of_val::<T>(&**self) + (**self).heap_size()
+  }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+  fn heap_size(&self) -> usize {
+    match *self {
+      Some(ref x) => x.heap_size(),
+      None => 0,
+    }
+  }
+}
+
+impl<T: HeapSize, E: HeapSize> HeapSize for Result<T, E> {
+  fn heap_size(&self) -> usize {
+    match *self {
+      Ok(ref r) => r.heap_size(),
+      Err(ref e) => e.heap_size(),
+    }
+  }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+  fn heap_size(&self) -> usize {
+    self.capacity() * size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+  }
+}
+
+/// The size of the table is approximated from the capacity of the map, with a control byte per
+/// entry.
+impl<K: Eq + Hash + HeapSize, V: HeapSize> HeapSize for HashMap<K, V> {
+  fn heap_size(&self) -> usize {
+    let table_size: usize = self.capacity() * (size_of::<(K, V)>() + 1);
+    table_size + self.iter().map(|(k, v)| k.heap_size() + v.heap_size()).sum::<usize>()
+  }
+}
+
+/// The size of the nodes is approximated from the number of entries of the map.
+impl<K: Eq + Ord + HeapSize, V: HeapSize> HeapSize for BTreeMap<K, V> {
+  fn heap_size(&self) -> usize {
+    let nodes_size: usize = self.len() * size_of::<(K, V)>();
+    nodes_size + self.iter().map(|(k, v)| k.heap_size() + v.heap_size()).sum::<usize>()
+  }
+}
+
+impl HeapSize for &str {}
@@ -1,90 +1,140 @@
 #![feature(dropck_eyepatch)]
 
-/// This module lets you create garbage-collected scopes
-///
-/// ```compile_fail
-/// use scoped_gc::{Gc, GcScope, Trace};
-///
-/// pub struct NamedObject {
-///   pub name: String,
-/// }
-///
-/// unsafe impl Trace for NamedObject {
-///   unsafe fn mark(&self) {}
-///   unsafe fn root(&self) {}
-///   unsafe fn unroot(&self) {}
-/// }
-///
-/// fn main() {
-///   let message: Gc<NamedObject>;
-///   {
-///     let scope: GcScope = GcScope::new();
-///     message = scope.alloc(NamedObject { name: String::from("Hello, World!") }).unwrap();
-///   }
-///   println!("{}", message.name);
-/// }
-/// ```
-///
-/// ```compile_fail
-/// use scoped_gc::{Gc, GcScope, Trace};
-///
-/// pub struct RefNamedObject<'a> {
-///   pub name: &'a str,
-/// }
-///
-/// unsafe impl<'a> Trace for RefNamedObject<'a> {
-///   unsafe fn mark(&self) {}
-///   unsafe fn root(&self) {}
-///   unsafe fn unroot(&self) {}
-/// }
-///
-/// fn main() {
-///   let scope: GcScope = GcScope::new();
-///   let message: Gc<RefNamedObject>;
-///   {
-///     let hello_world: String = String::from("Hello, World!");
-///     message = scope.alloc(RefNamedObject { name: &hello_world }).unwrap();
-///   }
-/// }
-/// ```
-///
-/// ```compile_fail
-/// // Check that the drop order between the GC scope and values is enforced.
-///
-/// use scoped_gc::{Gc, GcScope, Trace};
-///
-/// pub struct NamedObject {
-///   pub name: String,
-/// }
-///
-/// unsafe impl Trace for NamedObject {
-///   unsafe fn mark(&self) {}
-///   unsafe fn root(&self) {}
-///   unsafe fn unroot(&self) {}
-/// }
-///
-/// fn main() {
-///   let mut stack: Vec<Gc<NamedObject>> = Vec::new();
-///   let scope: GcScope = GcScope::new();
-///   stack.push(scope.alloc(NamedObject { name: String::from("Hello, World!") }).unwrap())
-///   // `scope` is dropped first and frees the `NamedObject`
-///   // `stack` is dropped second, but it contains a `Gc`!
-///   // It will try to decrement the root count of an already freed value
-/// }
-/// ```
+//! This module lets you create garbage-collected scopes
+//!
+//! ```compile_fail
+//! use scoped_gc::{Finalize, Gc, GcScope, HeapSize, Trace};
+//!
+//! pub struct NamedObject {
+//!   pub name: String,
+//! }
+//!
+//! impl Finalize for NamedObject {}
+//!
+//! impl HeapSize for NamedObject {}
+//!
+//! unsafe impl Trace for NamedObject {
+//!   unsafe fn mark(&self) {}
+//! }
+//!
+//! fn main() {
+//!   let message: Gc<NamedObject>;
+//!   {
+//!     let scope: GcScope = GcScope::new();
+//!     message = scope.alloc(NamedObject { name: String::from("Hello, World!") }).unwrap();
+//!   }
+//!   println!("{}", message.name);
+//! }
+//! ```
+//!
+//! ```compile_fail
+//! use scoped_gc::{Finalize, Gc, GcScope, HeapSize, Trace};
+//!
+//! pub struct RefNamedObject<'a> {
+//!   pub name: &'a str,
+//! }
+//!
+//! impl<'a> Finalize for RefNamedObject<'a> {}
+//!
+//! impl<'a> HeapSize for RefNamedObject<'a> {}
+//!
+//! unsafe impl<'a> Trace for RefNamedObject<'a> {
+//!   unsafe fn mark(&self) {}
+//! }
+//!
+//! fn main() {
+//!   let scope: GcScope = GcScope::new();
+//!   let message: Gc<RefNamedObject>;
+//!   {
+//!     let hello_world: String = String::from("Hello, World!");
+//!     message = scope.alloc(RefNamedObject { name: &hello_world }).unwrap();
+//!   }
+//! }
+//! ```
+//!
+//! ```compile_fail
+//! // Check that the drop order between the GC scope and values is enforced.
+//!
+//! use scoped_gc::{Finalize, Gc, GcScope, HeapSize, Trace};
+//!
+//! pub struct NamedObject {
+//!   pub name: String,
+//! }
+//!
+//! impl Finalize for NamedObject {}
+//!
+//! impl HeapSize for NamedObject {}
+//!
+//! unsafe impl Trace for NamedObject {
+//!   unsafe fn mark(&self) {}
+//! }
+//!
+//! fn main() {
+//!   let mut stack: Vec<Gc<NamedObject>> = Vec::new();
+//!   let scope: GcScope = GcScope::new();
+//!   stack.push(scope.alloc(NamedObject { name: String::from("Hello, World!") }).unwrap())
+//!   // `scope` is dropped first and frees the `NamedObject`
+//!   // `stack` is dropped second, but it contains a `Gc`!
+//!   // It will try to decrement the reference count of an already freed value
+//! }
+//! ```
 
+#[cfg(feature = "heap-profiler")]
+extern crate backtrace;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+mod arena_gc;
+mod collection_policy;
+mod finalization_registry;
+mod finalize;
 mod gc;
 mod gc_alloc_err;
+mod gc_arena;
 mod gc_box;
+mod gc_config;
+mod gc_hooks;
+mod gc_marker;
+mod gc_near_limit_action;
+mod gc_phase_span;
 mod gc_ref_cell;
 mod gc_scope;
+#[cfg(feature = "debug")]
+mod gc_site_stats;
+mod gc_stats;
+mod gc_type_stats;
+mod gc_weak;
+mod gc_weak_map;
+mod gc_weak_set;
+#[cfg(feature = "heap-profiler")]
+mod heap_profiler;
+mod heap_size;
+#[cfg(feature = "heap-profiler")]
+mod pprof_writer;
 mod trace;
 
 #[cfg(test)]
 mod test;
 
+pub use arena_gc::ArenaGc;
+pub use collection_policy::{AllocationCountPolicy, CollectionPolicy, GcCollectionKind, GcCollectionResult, GcHeapInfo};
+pub use collection_policy::{IntervalPolicy, NeverPolicy, ThresholdPolicy};
+pub use finalize::Finalize;
 pub use gc::Gc;
 pub use gc_alloc_err::GcAllocErr;
+pub use gc_arena::{GcArena, MutationContext, Rootable};
+pub use gc_config::GcConfig;
+pub use gc_near_limit_action::GcNearLimitAction;
 pub use gc_ref_cell::{GcRef, GcRefCell, GcRefMut};
 pub use gc_scope::GcScope;
+#[cfg(feature = "debug")]
+pub use gc_site_stats::GcSiteStats;
+pub use gc_stats::GcStats;
+pub use gc_type_stats::GcTypeStats;
+pub use gc_weak::GcWeak;
+pub use gc_weak_map::GcWeakMap;
+pub use gc_weak_set::GcWeakSet;
+pub use heap_size::HeapSize;
 pub use trace::Trace;
@@ -0,0 +1,163 @@
+use ::std::collections::HashMap;
+
+/// Internal struct encoding a heap profile in the pprof format (`profile.proto` of
+/// `github.com/google/pprof`).
+///
+/// The strings, functions and locations are deduplicated as they are added. The profile has two
+/// sample values: the number of objects and the number of bytes in use.
+#[derive(Debug, Default)]
+pub(crate) struct PprofWriter {
+  strings: Vec<String>,
+  string_ids: HashMap<String, u64>,
+  // Encoded `Function` messages, and their ids by name and file
+  functions: Vec<Vec<u8>>,
+  function_ids: HashMap<(u64, u64), u64>,
+  // Encoded `Location` messages, and their ids by address
+  locations: Vec<Vec<u8>>,
+  location_ids: HashMap<usize, u64>,
+  // Encoded `Sample` messages
+  samples: Vec<Vec<u8>>,
+}
+
+/// A source line of a location: function name, file name and line number.
+pub(crate) type PprofLine = (String, String, u32);
+
+// Protobuf field numbers of the `Profile` message
+const PROFILE_SAMPLE_TYPE: u32 = 1;
+const PROFILE_SAMPLE: u32 = 2;
+const PROFILE_LOCATION: u32 = 4;
+const PROFILE_FUNCTION: u32 = 5;
+const PROFILE_STRING_TABLE: u32 = 6;
+const PROFILE_PERIOD_TYPE: u32 = 11;
+const PROFILE_PERIOD: u32 = 12;
+
+// Protobuf wire types
+const WIRE_VARINT: u32 = 0;
+const WIRE_LENGTH_DELIMITED: u32 = 2;
+
+impl PprofWriter {
+  pub(crate) fn new() -> PprofWriter {
+    let mut writer: PprofWriter = PprofWriter::default();
+    // The first string of the table must be empty
+    writer.string_id("");
+    writer
+  }
+
+  fn string_id(&mut self, value: &str) -> u64 {
+    if let Some(&id) = self.string_ids.get(value) {
+      return id;
+    }
+    let id: u64 = self.strings.len() as u64;
+    self.strings.push(String::from(value));
+    self.string_ids.insert(String::from(value), id);
+    id
+  }
+
+  fn function_id(&mut self, name: &str, file: &str) -> u64 {
+    let key: (u64, u64) = (self.string_id(name), self.string_id(file));
+    if let Some(&id) = self.function_ids.get(&key) {
+      return id;
+    }
+    let id: u64 = self.functions.len() as u64 + 1;
+    let mut function: Vec<u8> = Vec::new();
+    write_varint_field(&mut function, 1, id);
+    write_varint_field(&mut function, 2, key.0);
+    write_varint_field(&mut function, 3, key.0);
+    write_varint_field(&mut function, 4, key.1);
+    self.functions.push(function);
+    self.function_ids.insert(key, id);
+    id
+  }
+
+  /// Returns the id of the location of `address`, adding it with its source lines (innermost first)
+  /// if it is new.
+  pub(crate) fn location_id(&mut self, address: usize, lines: &[PprofLine]) -> u64 {
+    if let Some(&id) = self.location_ids.get(&address) {
+      return id;
+    }
+    let id: u64 = self.locations.len() as u64 + 1;
+    let mut location: Vec<u8> = Vec::new();
+    write_varint_field(&mut location, 1, id);
+    write_varint_field(&mut location, 3, address as u64);
+    for &(ref function, ref file, line_number) in lines.iter() {
+      let mut line: Vec<u8> = Vec::new();
+      write_varint_field(&mut line, 1, self.function_id(function, file));
+      write_varint_field(&mut line, 2, u64::from(line_number));
+      write_bytes_field(&mut location, 4, &line);
+    }
+    self.locations.push(location);
+    self.location_ids.insert(address, id);
+    id
+  }
+
+  /// Adds a sample with its stack (innermost location first), labeled with the name of the type
+  /// of the sampled value.
+  pub(crate) fn add_sample(&mut self, location_ids: &[u64], type_name: &str, objects: u64, bytes: u64) {
+    let mut sample: Vec<u8> = Vec::new();
+    write_packed_field(&mut sample, 1, location_ids);
+    write_packed_field(&mut sample, 2, &[objects, bytes]);
+    let mut label: Vec<u8> = Vec::new();
+    write_varint_field(&mut label, 1, self.string_id("type"));
+    write_varint_field(&mut label, 2, self.string_id(type_name));
+    write_bytes_field(&mut sample, 3, &label);
+    self.samples.push(sample);
+  }
+
+  /// Encodes the profile, with the sampling period in bytes.
+  pub(crate) fn finish(mut self, period: usize) -> Vec<u8> {
+    let value_types: [(u64, u64); 3] = [
+      (self.string_id("inuse_objects"), self.string_id("count")),
+      (self.string_id("inuse_space"), self.string_id("bytes")),
+      (self.string_id("space"), self.string_id("bytes")),
+    ];
+    let mut profile: Vec<u8> = Vec::new();
+    for (index, &(value_type, unit)) in value_types.iter().enumerate() {
+      let mut message: Vec<u8> = Vec::new();
+      write_varint_field(&mut message, 1, value_type);
+      write_varint_field(&mut message, 2, unit);
+      let field: u32 = if index < 2 { PROFILE_SAMPLE_TYPE } else { PROFILE_PERIOD_TYPE };
+      write_bytes_field(&mut profile, field, &message);
+    }
+    for sample in self.samples.iter() {
+      write_bytes_field(&mut profile, PROFILE_SAMPLE, sample);
+    }
+    for location in self.locations.iter() {
+      write_bytes_field(&mut profile, PROFILE_LOCATION, location);
+    }
+    for function in self.functions.iter() {
+      write_bytes_field(&mut profile, PROFILE_FUNCTION, function);
+    }
+    for string in self.strings.iter() {
+      write_bytes_field(&mut profile, PROFILE_STRING_TABLE, string.as_bytes());
+    }
+    write_varint_field(&mut profile, PROFILE_PERIOD, period as u64);
+    profile
+  }
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    buffer.push((value as u8) | 0x80);
+    value >>= 7;
+  }
+  buffer.push(value as u8);
+}
+
+fn write_varint_field(buffer: &mut Vec<u8>, field: u32, value: u64) {
+  write_varint(buffer, u64::from(field << 3 | WIRE_VARINT));
+  write_varint(buffer, value);
+}
+
+fn write_bytes_field(buffer: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+  write_varint(buffer, u64::from(field << 3 | WIRE_LENGTH_DELIMITED));
+  write_varint(buffer, bytes.len() as u64);
+  buffer.extend_from_slice(bytes);
+}
+
+fn write_packed_field(buffer: &mut Vec<u8>, field: u32, values: &[u64]) {
+  let mut packed: Vec<u8> = Vec::new();
+  for &value in values.iter() {
+    write_varint(&mut packed, value);
+  }
+  write_bytes_field(buffer, field, &packed);
+}
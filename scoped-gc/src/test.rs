@@ -1,4 +1,10 @@
-use {Gc, GcRefCell, GcScope, Trace};
+use ::std::cell::{Cell, RefCell};
+use ::std::error::Error;
+use ::std::panic::{self, AssertUnwindSafe};
+use ::std::rc::Rc;
+use ::std::time::{Duration, Instant};
+use {AllocationCountPolicy, ArenaGc, CollectionPolicy, Gc, GcAllocErr, GcArena, GcCollectionResult, GcConfig, GcHeapInfo, GcStats, GcTypeStats};
+use {Finalize, GcNearLimitAction, GcRefCell, GcScope, GcWeak, GcWeakMap, GcWeakSet, HeapSize, NeverPolicy, Rootable, ThresholdPolicy, Trace};
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
@@ -7,10 +13,12 @@ pub struct RefNamedObject<'n> {
   pub name: &'n str,
 }
 
+impl<'a> Finalize for RefNamedObject<'a> {}
+
+impl<'a> HeapSize for RefNamedObject<'a> {}
+
 unsafe impl<'a> Trace for RefNamedObject<'a> {
   unsafe fn mark(&self) {}
-  unsafe fn root(&self) {}
-  unsafe fn unroot(&self) {}
 }
 
 #[derive(Debug)]
@@ -18,10 +26,364 @@ pub struct NamedObject {
   pub name: String,
 }
 
+impl Finalize for NamedObject {}
+
+impl HeapSize for NamedObject {}
+
 unsafe impl Trace for NamedObject {
   unsafe fn mark(&self) {}
-  unsafe fn root(&self) {}
-  unsafe fn unroot(&self) {}
+}
+
+/// Invariant in `'a`: the name can be replaced by any string of the same lifetime.
+#[derive(Debug)]
+pub struct InvariantObject<'a> {
+  pub name: Cell<&'a str>,
+}
+
+impl<'a> Finalize for InvariantObject<'a> {}
+
+impl<'a> HeapSize for InvariantObject<'a> {}
+
+unsafe impl<'a> Trace for InvariantObject<'a> {
+  unsafe fn mark(&self) {}
+}
+
+/// Increments a shared counter when dropped, to observe collections.
+#[derive(Debug)]
+pub struct DropCounter<'a> {
+  pub drops: &'a Cell<usize>,
+}
+
+impl<'a> Finalize for DropCounter<'a> {}
+
+impl<'a> HeapSize for DropCounter<'a> {}
+
+unsafe impl<'a> Trace for DropCounter<'a> {
+  unsafe fn mark(&self) {}
+}
+
+impl<'a> Drop for DropCounter<'a> {
+  fn drop(&mut self) {
+    self.drops.set(self.drops.get() + 1);
+  }
+}
+
+/// Increments a counter shared with the test when dropped, for values that can't borrow it.
+#[derive(Debug)]
+pub struct SharedDropCounter {
+  pub drops: Rc<Cell<usize>>,
+}
+
+impl Finalize for SharedDropCounter {}
+
+impl HeapSize for SharedDropCounter {}
+
+unsafe impl Trace for SharedDropCounter {
+  unsafe fn mark(&self) {}
+}
+
+impl Drop for SharedDropCounter {
+  fn drop(&mut self) {
+    self.drops.set(self.drops.get() + 1);
+  }
+}
+
+#[derive(Debug)]
+pub struct Holder<'a> {
+  pub value: Option<Gc<'a, DropCounter<'a>>>,
+}
+
+impl<'a> Finalize for Holder<'a> {}
+
+impl<'a> HeapSize for Holder<'a> {}
+
+unsafe impl<'a> Trace for Holder<'a> {
+  unsafe fn mark(&self) {
+    self.value.mark();
+  }
+}
+
+/// Stores its target in `keeper` when finalized, resurrecting it.
+#[derive(Debug)]
+pub struct Resurrector<'a> {
+  pub target: Gc<'a, DropCounter<'a>>,
+  pub keeper: Gc<'a, GcRefCell<Holder<'a>>>,
+}
+
+impl<'a> Finalize for Resurrector<'a> {
+  fn finalize(&self) {
+    self.keeper.borrow_mut().value = Some(Gc::clone(&self.target));
+  }
+}
+
+impl<'a> HeapSize for Resurrector<'a> {}
+
+unsafe impl<'a> Trace for Resurrector<'a> {
+  unsafe fn mark(&self) {
+    self.target.mark();
+    self.keeper.mark();
+  }
+}
+
+/// Stores its target in `keeper` when finalized, resurrecting it in an arena.
+#[derive(Debug)]
+pub struct ArenaResurrector<'gc> {
+  pub target: ArenaGc<'gc, SharedDropCounter>,
+  pub keeper: ArenaGc<'gc, GcRefCell<Option<ArenaGc<'gc, SharedDropCounter>>>>,
+}
+
+impl<'gc> Finalize for ArenaResurrector<'gc> {
+  fn finalize(&self) {
+    *self.keeper.borrow_mut() = Some(self.target);
+  }
+}
+
+impl<'gc> HeapSize for ArenaResurrector<'gc> {}
+
+unsafe impl<'gc> Trace for ArenaResurrector<'gc> {
+  unsafe fn mark(&self) {
+    self.target.mark();
+    self.keeper.mark();
+  }
+}
+
+/// Downgrades its targets when finalized.
+#[derive(Debug)]
+pub struct Downgrader<'a> {
+  pub targets: Vec<Gc<'a, DropCounter<'a>>>,
+  pub weaks: Rc<RefCell<Vec<GcWeak<'a, DropCounter<'a>>>>>,
+}
+
+impl<'a> Finalize for Downgrader<'a> {
+  fn finalize(&self) {
+    for target in self.targets.iter() {
+      self.weaks.borrow_mut().push(Gc::downgrade(target));
+    }
+  }
+}
+
+impl<'a> HeapSize for Downgrader<'a> {}
+
+unsafe impl<'a> Trace for Downgrader<'a> {
+  unsafe fn mark(&self) {
+    self.targets.mark();
+  }
+}
+
+/// Clones its target into `stash` when dropped.
+pub struct DropCloner<'a> {
+  pub target: Gc<'a, DropCounter<'a>>,
+  pub stash: Rc<RefCell<Vec<Gc<'a, DropCounter<'a>>>>>,
+}
+
+impl<'a> Finalize for DropCloner<'a> {}
+
+impl<'a> HeapSize for DropCloner<'a> {}
+
+unsafe impl<'a> Trace for DropCloner<'a> {
+  unsafe fn mark(&self) {
+    self.target.mark();
+  }
+}
+
+impl<'a> Drop for DropCloner<'a> {
+  fn drop(&mut self) {
+    self.stash.borrow_mut().push(Gc::clone(&self.target));
+  }
+}
+
+/// Moves its target into `stash` when dropped.
+pub struct DropMover<'a> {
+  pub target: Option<Gc<'a, DropCounter<'a>>>,
+  pub stash: Rc<RefCell<Vec<Gc<'a, DropCounter<'a>>>>>,
+}
+
+impl<'a> Finalize for DropMover<'a> {}
+
+impl<'a> HeapSize for DropMover<'a> {}
+
+unsafe impl<'a> Trace for DropMover<'a> {
+  unsafe fn mark(&self) {
+    self.target.mark();
+  }
+}
+
+impl<'a> Drop for DropMover<'a> {
+  fn drop(&mut self) {
+    self.stash.borrow_mut().extend(self.target.take());
+  }
+}
+
+/// Allocates a notification in its scope when dropped.
+pub struct Notifier<'a> {
+  pub scope: &'a GcScope<'a>,
+  pub notifications: Rc<RefCell<Vec<Gc<'a, NamedObject>>>>,
+}
+
+impl<'a> Finalize for Notifier<'a> {}
+
+impl<'a> HeapSize for Notifier<'a> {}
+
+unsafe impl<'a> Trace for Notifier<'a> {
+  unsafe fn mark(&self) {}
+}
+
+impl<'a> Drop for Notifier<'a> {
+  fn drop(&mut self) {
+    let notification = self.scope.alloc(NamedObject { name: String::from("dropped") }).unwrap();
+    self.notifications.borrow_mut().push(notification);
+    // The scope is already collecting garbage: this does nothing
+    self.scope.collect_garbage();
+  }
+}
+
+/// Allocates a counter in its scope when finalized, then tries to collect garbage and walk the
+/// heap.
+pub struct FinalizeAllocator<'a> {
+  pub scope: &'a GcScope<'a>,
+  pub drops: Rc<Cell<usize>>,
+  pub walked: Rc<Cell<usize>>,
+}
+
+impl<'a> Finalize for FinalizeAllocator<'a> {
+  fn finalize(&self) {
+    self.scope.alloc(SharedDropCounter { drops: Rc::clone(&self.drops) }).unwrap();
+    self.scope.collect_garbage();
+    self.scope.collect_step(1);
+    let walked: Rc<Cell<usize>> = Rc::clone(&self.walked);
+    self.scope.for_each_live(move |_| walked.set(walked.get() + 1));
+  }
+}
+
+impl<'a> HeapSize for FinalizeAllocator<'a> {}
+
+unsafe impl<'a> Trace for FinalizeAllocator<'a> {
+  unsafe fn mark(&self) {}
+}
+
+/// Panics after visiting its target while `panicking` is set.
+pub struct PanickingTrace<'a> {
+  pub target: Gc<'a, DropCounter<'a>>,
+  pub panicking: &'a Cell<bool>,
+}
+
+impl<'a> Finalize for PanickingTrace<'a> {}
+
+impl<'a> HeapSize for PanickingTrace<'a> {}
+
+unsafe impl<'a> Trace for PanickingTrace<'a> {
+  unsafe fn mark(&self) {
+    self.target.mark();
+    if self.panicking.get() {
+      panic!("PanickingTrace::mark");
+    }
+  }
+}
+
+/// Panics when dropped.
+pub struct PanickingDrop;
+
+impl Finalize for PanickingDrop {}
+
+impl HeapSize for PanickingDrop {}
+
+unsafe impl Trace for PanickingDrop {
+  unsafe fn mark(&self) {}
+}
+
+impl Drop for PanickingDrop {
+  fn drop(&mut self) {
+    panic!("PanickingDrop::drop");
+  }
+}
+
+/// Owns external memory reported to its scope.
+pub struct ExternalBuffer<'a> {
+  pub scope: &'a GcScope<'a>,
+  pub bytes: usize,
+}
+
+impl<'a> Finalize for ExternalBuffer<'a> {}
+
+impl<'a> HeapSize for ExternalBuffer<'a> {}
+
+unsafe impl<'a> Trace for ExternalBuffer<'a> {
+  unsafe fn mark(&self) {}
+}
+
+impl<'a> Drop for ExternalBuffer<'a> {
+  fn drop(&mut self) {
+    self.scope.report_external_free(self.bytes);
+  }
+}
+
+/// Records whether its weak pointer could be upgraded when it was dropped.
+#[derive(Debug)]
+pub struct WeakProbe<'a> {
+  pub target: GcWeak<'a, DropCounter<'a>>,
+  pub upgraded: &'a Cell<Option<bool>>,
+}
+
+impl<'a> Finalize for WeakProbe<'a> {}
+
+impl<'a> HeapSize for WeakProbe<'a> {}
+
+unsafe impl<'a> Trace for WeakProbe<'a> {
+  unsafe fn mark(&self) {
+    self.target.mark();
+  }
+}
+
+impl<'a> Drop for WeakProbe<'a> {
+  fn drop(&mut self) {
+    self.upgraded.set(Some(self.target.upgrade().is_some()));
+  }
+}
+
+/// Logs its finalization and its destruction, where dereferencing `other` is rejected.
+#[derive(Debug)]
+pub struct LoggedObject<'a> {
+  pub name: String,
+  pub other: GcRefCell<Option<Gc<'a, LoggedObject<'a>>>>,
+  pub log: &'a RefCell<Vec<String>>,
+}
+
+impl<'a> Finalize for LoggedObject<'a> {
+  fn finalize(&self) {
+    let other: String = self.other.borrow().as_ref().map(|other| other.name.clone()).unwrap_or_default();
+    self.log.borrow_mut().push(format!("finalize {} -> {}", self.name, other));
+  }
+}
+
+impl<'a> HeapSize for LoggedObject<'a> {}
+
+unsafe impl<'a> Trace for LoggedObject<'a> {
+  unsafe fn mark(&self) {
+    self.other.mark();
+  }
+}
+
+impl<'a> Drop for LoggedObject<'a> {
+  fn drop(&mut self) {
+    let other = self.other.borrow();
+    let rejected: bool = panic::catch_unwind(AssertUnwindSafe(|| other.as_ref().map(|other| other.name.len()))).is_err();
+    self.log.borrow_mut().push(format!("drop {} rejected={}", self.name, rejected));
+  }
+}
+
+#[derive(Debug)]
+pub struct ListNode<'a> {
+  pub next: Option<Gc<'a, GcRefCell<ListNode<'a>>>>,
+}
+
+impl<'a> Finalize for ListNode<'a> {}
+
+impl<'a> HeapSize for ListNode<'a> {}
+
+unsafe impl<'a> Trace for ListNode<'a> {
+  unsafe fn mark(&self) {
+    self.next.mark();
+  }
 }
 
 #[derive(Debug)]
@@ -30,16 +392,14 @@ pub struct CircularNamedObject<'a> {
   pub other: Option<Gc<'a, GcRefCell<CircularNamedObject<'a>>>>,
 }
 
+impl<'a> Finalize for CircularNamedObject<'a> {}
+
+impl<'a> HeapSize for CircularNamedObject<'a> {}
+
 unsafe impl<'a> Trace for CircularNamedObject<'a> {
   unsafe fn mark(&self) {
     self.other.mark();
   }
-  unsafe fn root(&self) {
-    self.other.root();
-  }
-  unsafe fn unroot(&self) {
-    self.other.unroot();
-  }
 }
 #[derive(Debug)]
 pub struct TreeNode<'a> {
@@ -47,19 +407,15 @@ pub struct TreeNode<'a> {
   pub children: Vec<Gc<'a, GcRefCell<TreeNode<'a>>>>,
 }
 
+impl<'a> Finalize for TreeNode<'a> {}
+
+impl<'a> HeapSize for TreeNode<'a> {}
+
 unsafe impl<'a> Trace for TreeNode<'a> {
   unsafe fn mark(&self) {
     self.parent.mark();
     self.children.mark();
   }
-  unsafe fn root(&self) {
-    self.parent.root();
-    self.children.root();
-  }
-  unsafe fn unroot(&self) {
-    self.parent.unroot();
-    self.children.unroot();
-  }
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
@@ -98,6 +454,7 @@ fn test_gc_circular() {
   let n2 = scope.alloc(GcRefCell::new(CircularNamedObject { name: String::from("n2"), other: None })).unwrap();
   n1.borrow_mut().other = Some(Gc::clone(&n2));
   n2.borrow_mut().other = Some(Gc::clone(&n1));
+  assert_eq!(n1.borrow().other.as_ref().unwrap().borrow().name, String::from("n2"));
 }
 
 #[test]
@@ -114,3 +471,964 @@ fn test_gc_tree() {
   root.borrow_mut().children.push(Gc::clone(&child2));
   child2.borrow_mut().parent = Some(Gc::clone(&root));
 }
+
+#[test]
+fn test_gc_auto_collect() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig { threshold: Some(1024), growth_factor: 2.0 });
+  for _ in 0..1000 {
+    scope.alloc(DropCounter { drops: &drops }).unwrap();
+  }
+  assert!(drops.get() > 0);
+}
+
+#[test]
+fn test_gc_manual_collect() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  for _ in 0..1000 {
+    scope.alloc(DropCounter { drops: &drops }).unwrap();
+  }
+  assert_eq!(drops.get(), 0);
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1000);
+}
+
+#[test]
+fn test_gc_allocation_count_policy() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_policy(AllocationCountPolicy::new(10));
+  for _ in 0..10 {
+    scope.alloc(DropCounter { drops: &drops }).unwrap();
+  }
+  assert_eq!(drops.get(), 0);
+  scope.alloc(DropCounter { drops: &drops }).unwrap();
+  assert_eq!(drops.get(), 10);
+}
+
+#[test]
+fn test_gc_maybe_collect() {
+  let drops: Cell<usize> = Cell::new(0);
+  {
+    let scope: GcScope = GcScope::with_policy(NeverPolicy);
+    scope.alloc(DropCounter { drops: &drops }).unwrap();
+    assert!(!scope.maybe_collect());
+    assert_eq!(drops.get(), 0);
+  }
+  {
+    let scope: GcScope = GcScope::with_policy(AllocationCountPolicy::new(1));
+    scope.alloc(DropCounter { drops: &drops }).unwrap();
+    assert!(scope.maybe_collect());
+    assert_eq!(drops.get(), 2);
+  }
+}
+
+#[test]
+fn test_gc_policy_on_collect() {
+  #[derive(Debug)]
+  struct RecordingPolicy {
+    results: Rc<Cell<Option<GcCollectionResult>>>,
+  }
+
+  impl CollectionPolicy for RecordingPolicy {
+    fn should_collect(&mut self, _heap: &GcHeapInfo) -> bool {
+      false
+    }
+
+    fn on_collect(&mut self, result: &GcCollectionResult) {
+      self.results.set(Some(*result));
+    }
+  }
+
+  let drops: Cell<usize> = Cell::new(0);
+  let results: Rc<Cell<Option<GcCollectionResult>>> = Rc::new(Cell::new(None));
+  let scope: GcScope = GcScope::with_policy(RecordingPolicy { results: Rc::clone(&results) });
+  let _kept = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  scope.alloc(DropCounter { drops: &drops }).unwrap();
+  scope.collect_garbage();
+  let result: GcCollectionResult = results.get().unwrap();
+  assert_eq!(result.freed_objects, 1);
+  assert_eq!(result.live_objects, 1);
+  assert_eq!(result.freed_bytes, result.live_bytes);
+}
+
+#[test]
+fn test_gc_threshold_policy() {
+  let mut policy: ThresholdPolicy = ThresholdPolicy::new(100, 2.0);
+  assert_eq!(policy.threshold(), Some(100));
+  policy.on_collect(&GcCollectionResult { live_bytes: 80, external_bytes: 10, ..GcCollectionResult::default() });
+  assert_eq!(policy.threshold(), Some(180));
+  policy.on_collect(&GcCollectionResult { live_bytes: 20, ..GcCollectionResult::default() });
+  assert_eq!(policy.threshold(), Some(100));
+}
+
+#[test]
+fn test_gc_collect_step() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let _kept = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  for _ in 0..100 {
+    scope.alloc(DropCounter { drops: &drops }).unwrap();
+  }
+  let mut steps: usize = 1;
+  while !scope.collect_step(10) {
+    steps += 1;
+  }
+  assert!(steps > 1);
+  assert_eq!(drops.get(), 100);
+}
+
+#[test]
+fn test_gc_collect_step_write_barrier() {
+  // The value starts in either holder, so it is moved before and after each holder is traced
+  for start in 0..2 {
+    let drops: Cell<usize> = Cell::new(0);
+    let scope: GcScope = GcScope::with_config(GcConfig::manual());
+    let left = scope.alloc(GcRefCell::new(Holder { value: None })).unwrap();
+    let right = scope.alloc(GcRefCell::new(Holder { value: None })).unwrap();
+    for _ in 0..100 {
+      scope.alloc(GcRefCell::new(Holder { value: None })).unwrap();
+    }
+    let first = if start == 0 { &left } else { &right };
+    first.borrow_mut().value = Some(scope.alloc(DropCounter { drops: &drops }).unwrap());
+    // Count the references of the 103 boxes: the value is only referenced by its holder
+    scope.collect_step(150);
+    // Move the value back and forth between two holders while the collection is in progress
+    loop {
+      let done: bool = scope.collect_step(1);
+      let (from, to) = if left.borrow().value.is_some() { (&left, &right) } else { (&right, &left) };
+      let value = from.borrow_mut().value.take();
+      to.borrow_mut().value = value;
+      if done {
+        break;
+      }
+    }
+    scope.collect_garbage();
+    assert_eq!(drops.get(), 0);
+    let holder = if left.borrow().value.is_some() { &left } else { &right };
+    assert_eq!(holder.borrow().value.as_ref().unwrap().drops.get(), 0);
+  }
+}
+
+#[test]
+fn test_gc_collect_until() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  for _ in 0..100 {
+    scope.alloc(DropCounter { drops: &drops }).unwrap();
+  }
+  while !scope.collect_until(Instant::now() + Duration::from_millis(1)) {}
+  assert_eq!(drops.get(), 100);
+}
+
+#[test]
+fn test_gc_minor_collection() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  scope.set_generational(true);
+  let kept = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  for _ in 0..10 {
+    scope.alloc(DropCounter { drops: &drops }).unwrap();
+  }
+  scope.collect_minor();
+  assert_eq!(drops.get(), 10);
+  // `kept` was promoted: it is only freed by a full collection
+  ::std::mem::drop(kept);
+  scope.collect_minor();
+  assert_eq!(drops.get(), 10);
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 11);
+}
+
+#[test]
+fn test_gc_minor_collection_old_to_young() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  scope.set_generational(true);
+  let old = scope.alloc(GcRefCell::new(Holder { value: None })).unwrap();
+  scope.collect_minor();
+  // Create an old-to-young edge
+  old.borrow_mut().value = Some(scope.alloc(DropCounter { drops: &drops }).unwrap());
+  scope.collect_minor();
+  assert_eq!(drops.get(), 0);
+  assert_eq!(old.borrow().value.as_ref().unwrap().drops.get(), 0);
+  old.borrow_mut().value = None;
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn test_gc_generational_auto_collect() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_policy(AllocationCountPolicy::new(10));
+  scope.set_generational(true);
+  let kept = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  for _ in 0..100 {
+    scope.alloc(DropCounter { drops: &drops }).unwrap();
+  }
+  assert!(drops.get() >= 90);
+  assert_eq!(kept.drops.get(), drops.get());
+}
+
+#[test]
+fn test_gc_stored_pointers_are_not_roots() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let value = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  let holder = scope.alloc(GcRefCell::new(Holder { value: Some(Gc::clone(&value)) })).unwrap();
+  drop(value);
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 0);
+  // The only pointer to the value is inside managed memory: it is not a root
+  holder.borrow_mut().value = None;
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1);
+  drop(holder);
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn test_gc_arena() {
+  struct CountersRoot;
+
+  impl<'gc> Rootable<'gc> for CountersRoot {
+    type Root = ArenaGc<'gc, GcRefCell<Vec<ArenaGc<'gc, SharedDropCounter>>>>;
+  }
+
+  let drops: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+  let mut arena: GcArena<CountersRoot> = GcArena::new(|mc| mc.alloc(GcRefCell::new(Vec::new())).unwrap());
+  arena.mutate(|mc, root| {
+    for i in 0..20 {
+      let counter = mc.alloc(SharedDropCounter { drops: Rc::clone(&drops) }).unwrap();
+      if i % 2 == 0 {
+        root.borrow_mut().push(counter);
+      }
+    }
+  });
+  arena.collect_garbage();
+  assert_eq!(drops.get(), 10);
+  arena.mutate(|_, root| {
+    assert_eq!(root.borrow().len(), 10);
+    root.borrow_mut().truncate(5);
+  });
+  arena.collect_garbage();
+  assert_eq!(drops.get(), 15);
+  drop(arena);
+  assert_eq!(drops.get(), 20);
+}
+
+#[test]
+fn test_gc_weak() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let value = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  let weak = Gc::downgrade(&value);
+  scope.collect_garbage();
+  assert_eq!(weak.upgrade().unwrap().drops.get(), 0);
+  drop(value);
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1);
+  assert!(weak.upgrade().is_none());
+  assert!(GcWeak::clone(&weak).upgrade().is_none());
+}
+
+#[test]
+fn test_gc_weak_cleared_before_drop() {
+  let drops: Cell<usize> = Cell::new(0);
+  let upgraded: Cell<Option<bool>> = Cell::new(None);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  {
+    let target = scope.alloc(DropCounter { drops: &drops }).unwrap();
+    scope.alloc(WeakProbe { target: Gc::downgrade(&target), upgraded: &upgraded }).unwrap();
+  }
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1);
+  assert_eq!(upgraded.get(), Some(false));
+}
+
+#[test]
+fn test_gc_weak_map() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let map = scope.alloc(GcRefCell::new(GcWeakMap::new())).unwrap();
+  let key1 = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  {
+    // `key2` is only reachable through the value of `key1`, and `value` through the value of `key2`
+    let key2 = scope.alloc(DropCounter { drops: &drops }).unwrap();
+    let value = scope.alloc(DropCounter { drops: &drops }).unwrap();
+    map.borrow_mut().insert(&key2, value);
+    map.borrow_mut().insert(&key1, key2);
+  }
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 0);
+  assert_eq!(map.borrow().len(), 2);
+  let key2 = Gc::clone(&*map.borrow().get(&key1).unwrap());
+  assert!(map.borrow().contains_key(&key2));
+  drop(key2);
+  drop(key1);
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 3);
+  assert!(map.borrow().is_empty());
+}
+
+#[test]
+fn test_gc_unmanaged_weak_map() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let mut map: GcWeakMap<DropCounter, Gc<DropCounter>> = GcWeakMap::new();
+  let kept = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  {
+    let key = scope.alloc(DropCounter { drops: &drops }).unwrap();
+    map.insert(&key, scope.alloc(DropCounter { drops: &drops }).unwrap());
+    map.insert(&kept, scope.alloc(DropCounter { drops: &drops }).unwrap());
+  }
+  // The map is not reached by the collection: its entry is removed once its key is collected,
+  // and its value is collected by the next collection
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1);
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 2);
+  assert_eq!(map.len(), 1);
+  assert!(map.contains_key(&kept));
+}
+
+#[test]
+fn test_gc_weak_set() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let set = scope.alloc(GcRefCell::new(GcWeakSet::new())).unwrap();
+  let kept = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  let removed = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  assert!(set.borrow_mut().insert(&kept));
+  assert!(!set.borrow_mut().insert(&kept));
+  assert!(set.borrow_mut().insert(&removed));
+  drop(removed);
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1);
+  assert_eq!(set.borrow().len(), 1);
+  assert!(set.borrow().contains(&kept));
+  assert!(set.borrow_mut().remove(&kept));
+  assert!(set.borrow().is_empty());
+}
+
+#[test]
+fn test_gc_finalize() {
+  let log: RefCell<Vec<String>> = RefCell::new(Vec::new());
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  {
+    let a = scope.alloc(LoggedObject { name: String::from("a"), other: GcRefCell::new(None), log: &log }).unwrap();
+    let b = scope.alloc(LoggedObject { name: String::from("b"), other: GcRefCell::new(None), log: &log }).unwrap();
+    *a.other.borrow_mut() = Some(Gc::clone(&b));
+    *b.other.borrow_mut() = Some(Gc::clone(&a));
+  }
+  scope.collect_garbage();
+  let mut log: Vec<String> = log.into_inner();
+  // Both values are finalized before any of them is dropped
+  log[..2].sort();
+  log[2..].sort();
+  assert_eq!(log, vec!["finalize a -> b", "finalize b -> a", "drop a rejected=true", "drop b rejected=true"]);
+}
+
+#[test]
+fn test_gc_finalization_registry() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let kept = scope.alloc(NamedObject { name: String::from("kept") }).unwrap();
+  scope.register_finalizer(&kept, String::from("kept handle"));
+  {
+    let collected = scope.alloc(NamedObject { name: String::from("collected") }).unwrap();
+    scope.register_finalizer(&collected, 42u32);
+  }
+  assert!(scope.take_finalized().is_empty());
+  scope.collect_garbage();
+  let finalized: Vec<u32> = scope.take_finalized().into_iter()
+    .map(|held| *held.downcast::<u32>().unwrap())
+    .collect();
+  assert_eq!(finalized, vec![42]);
+  scope.collect_garbage();
+  assert!(scope.take_finalized().is_empty());
+  assert_eq!(kept.name, "kept");
+}
+
+#[test]
+fn test_gc_resurrection() {
+  let drops: Cell<usize> = Cell::new(0);
+  let resurrected: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  {
+    let resurrected = Rc::clone(&resurrected);
+    scope.set_resurrection_hook(move |_| resurrected.set(resurrected.get() + 1));
+  }
+  let keeper = scope.alloc(GcRefCell::new(Holder { value: None })).unwrap();
+  {
+    let target = scope.alloc(DropCounter { drops: &drops }).unwrap();
+    scope.alloc(Resurrector { target, keeper: Gc::clone(&keeper) }).unwrap();
+  }
+  scope.collect_garbage();
+  assert_eq!(resurrected.get(), 1);
+  assert_eq!(drops.get(), 0);
+  assert!(keeper.borrow().value.is_some());
+  keeper.borrow_mut().value = None;
+  scope.collect_garbage();
+  assert_eq!(resurrected.get(), 1);
+  assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn test_gc_downgrade_from_finalizer() {
+  let drops: Cell<usize> = Cell::new(0);
+  let weaks: Rc<RefCell<Vec<GcWeak<DropCounter>>>> = Rc::new(RefCell::new(Vec::new()));
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  {
+    // The targets are finalized before and after the downgrader
+    let before = scope.alloc(DropCounter { drops: &drops }).unwrap();
+    let downgrader = scope.alloc(Downgrader { targets: vec![before], weaks: Rc::clone(&weaks) }).unwrap();
+    let after = scope.alloc(DropCounter { drops: &drops }).unwrap();
+    let _other = scope.alloc(Downgrader { targets: vec![after, Gc::clone(&downgrader.targets[0])], weaks: Rc::clone(&weaks) }).unwrap();
+  }
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 2);
+  assert_eq!(weaks.borrow().len(), 3);
+  for weak in weaks.borrow().iter() {
+    assert!(weak.upgrade().is_none());
+  }
+  // The scope does not keep track of the dropped boxes
+  scope.collect_garbage();
+}
+
+#[test]
+fn test_gc_arena_resurrection() {
+  struct KeeperRoot;
+
+  impl<'gc> Rootable<'gc> for KeeperRoot {
+    type Root = ArenaGc<'gc, GcRefCell<Option<ArenaGc<'gc, SharedDropCounter>>>>;
+  }
+
+  let drops: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+  let mut arena: GcArena<KeeperRoot> = GcArena::new(|mc| mc.alloc(GcRefCell::new(None)).unwrap());
+  arena.mutate(|mc, root| {
+    let target = mc.alloc(SharedDropCounter { drops: Rc::clone(&drops) }).unwrap();
+    mc.alloc(ArenaResurrector { target, keeper: *root }).unwrap();
+  });
+  arena.collect_garbage();
+  assert_eq!(drops.get(), 0);
+  arena.mutate(|_, root| {
+    assert_eq!(root.borrow().unwrap().drops.get(), 0);
+    *root.borrow_mut() = None;
+  });
+  arena.collect_garbage();
+  assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn test_gc_clone_from_drop() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let stash: Rc<RefCell<Vec<Gc<DropCounter>>>> = Rc::new(RefCell::new(Vec::new()));
+  {
+    let target = scope.alloc(DropCounter { drops: &drops }).unwrap();
+    scope.alloc(DropCloner { target, stash: Rc::clone(&stash) }).unwrap();
+  }
+  // The dead target can't be cloned: the clone would outlive its box
+  assert!(panic::catch_unwind(AssertUnwindSafe(|| scope.collect_garbage())).is_err());
+  assert!(stash.borrow().is_empty());
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1);
+  assert_eq!(scope.stats().live_objects, 0);
+}
+
+#[test]
+fn test_gc_move_from_drop() {
+  let drops: Cell<usize> = Cell::new(0);
+  let frees: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  {
+    let frees = Rc::clone(&frees);
+    scope.set_free_hook(move |_, _| frees.set(frees.get() + 1));
+  }
+  let stash: Rc<RefCell<Vec<Gc<DropCounter>>>> = Rc::new(RefCell::new(Vec::new()));
+  {
+    let target = scope.alloc(DropCounter { drops: &drops }).unwrap();
+    scope.alloc(DropMover { target: Some(target), stash: Rc::clone(&stash) }).unwrap();
+  }
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1);
+  // The target escaped the collection: its box is kept until the pointer is dropped
+  assert_eq!(stash.borrow().len(), 1);
+  assert_eq!(frees.get(), 1);
+  assert!(panic::catch_unwind(AssertUnwindSafe(|| stash.borrow()[0].drops.get())).is_err());
+  scope.collect_garbage();
+  assert_eq!(frees.get(), 1);
+  stash.borrow_mut().clear();
+  scope.collect_garbage();
+  assert_eq!(frees.get(), 2);
+  assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn test_gc_alloc_from_drop() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let notifications: Rc<RefCell<Vec<Gc<NamedObject>>>> = Rc::new(RefCell::new(Vec::new()));
+  scope.alloc(Notifier { scope: &scope, notifications: Rc::clone(&notifications) }).unwrap();
+  scope.collect_garbage();
+  assert_eq!(notifications.borrow().len(), 1);
+  assert_eq!(notifications.borrow()[0].name, "dropped");
+  // The notification is managed by the scope
+  notifications.borrow_mut().clear();
+  let _counter = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 0);
+}
+
+#[test]
+fn test_gc_alloc_during_scope_drop() {
+  let drops: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+  let walked: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+  {
+    let scope: GcScope = GcScope::with_policy(AllocationCountPolicy::new(1));
+    let notifications: Rc<RefCell<Vec<Gc<NamedObject>>>> = Rc::new(RefCell::new(Vec::new()));
+    let _counter = scope.alloc(SharedDropCounter { drops: Rc::clone(&drops) }).unwrap();
+    let _allocator = scope.alloc(FinalizeAllocator { scope: &scope, drops: Rc::clone(&drops), walked: Rc::clone(&walked) }).unwrap();
+    let _notifier = scope.alloc(Notifier { scope: &scope, notifications: Rc::clone(&notifications) }).unwrap();
+    assert_eq!(drops.get(), 0);
+  }
+  // The counters allocated by the scope and by the finalizer are both dropped
+  assert_eq!(drops.get(), 2);
+  assert_eq!(walked.get(), 0);
+}
+
+#[test]
+fn test_gc_panicking_trace() {
+  let drops: Cell<usize> = Cell::new(0);
+  let panicking: Cell<bool> = Cell::new(true);
+  let resurrected: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  {
+    let resurrected = Rc::clone(&resurrected);
+    scope.set_resurrection_hook(move |_| resurrected.set(resurrected.get() + 1));
+  }
+  let kept = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  scope.alloc(PanickingTrace { target: Gc::clone(&kept), panicking: &panicking }).unwrap();
+  for _ in 0..2 {
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| scope.collect_garbage())).is_err());
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| scope.collect_step(1))).is_err());
+  }
+  panicking.set(false);
+  // The references counted before the panics are forgotten: `kept` is still a root, it is not
+  // finalized then resurrected
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 0);
+  assert_eq!(resurrected.get(), 0);
+  drop(kept);
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn test_gc_panicking_drop() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let kept = scope.alloc(NamedObject { name: String::from("kept") }).unwrap();
+  scope.alloc(DropCounter { drops: &drops }).unwrap();
+  scope.alloc(PanickingDrop).unwrap();
+  scope.alloc(DropCounter { drops: &drops }).unwrap();
+  assert!(panic::catch_unwind(AssertUnwindSafe(|| scope.collect_garbage())).is_err());
+  // Values can be used again, and the next collection drops the remaining dead values
+  assert_eq!(kept.name, "kept");
+  scope.collect_garbage();
+  assert_eq!(drops.get(), 2);
+  assert_eq!(kept.name, "kept");
+}
+
+#[test]
+fn test_gc_byte_limit() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let _a = scope.alloc(NamedObject { name: String::from("a") }).unwrap();
+  let size: usize = scope.allocated_bytes();
+  scope.set_byte_limit(Some(2 * size));
+  scope.alloc(NamedObject { name: String::from("b") }).unwrap();
+  // Reaching the limit collects `b`
+  let _c = scope.alloc(NamedObject { name: String::from("c") }).unwrap();
+  assert_eq!(scope.allocated_bytes(), 2 * size);
+  let result: Result<(), Box<dyn Error>> = (|| {
+    scope.alloc(NamedObject { name: String::from("d") })?;
+    Ok(())
+  })();
+  assert_eq!(result.unwrap_err().to_string(), GcAllocErr::Exhausted.to_string());
+  assert_eq!(scope.allocated_bytes(), 2 * size);
+  scope.set_byte_limit(None);
+  assert!(scope.alloc(NamedObject { name: String::from("d") }).is_ok());
+}
+
+#[test]
+fn test_gc_near_limit_callback() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let _a = scope.alloc(NamedObject { name: String::from("a") }).unwrap();
+  let size: usize = scope.allocated_bytes();
+  scope.set_byte_limit(Some(4 * size));
+  let calls: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+  {
+    let calls = Rc::clone(&calls);
+    scope.set_near_limit_callback(0.5, move |heap, limit| {
+      calls.set(calls.get() + 1);
+      match calls.get() {
+        1 => {
+          assert_eq!((heap.allocated_bytes, limit), (size, 4 * size));
+          GcNearLimitAction::SetLimit(8 * size)
+        }
+        2 => GcNearLimitAction::Fail,
+        _ => GcNearLimitAction::Collect,
+      }
+    });
+  }
+  // Reaches half of the limit: raised to `8 * size`
+  let _b = scope.alloc(NamedObject { name: String::from("b") }).unwrap();
+  assert_eq!((calls.get(), scope.byte_limit()), (1, Some(8 * size)));
+  scope.alloc(NamedObject { name: String::from("c") }).unwrap();
+  assert_eq!(calls.get(), 1);
+  // Reaches half of the new limit: failed, then collects `c`
+  assert_eq!(scope.alloc(NamedObject { name: String::from("d") }).unwrap_err(), GcAllocErr::Exhausted);
+  let _d = scope.alloc(NamedObject { name: String::from("d") }).unwrap();
+  assert_eq!(calls.get(), 3);
+  assert_eq!(scope.allocated_bytes(), 3 * size);
+}
+
+#[test]
+fn test_gc_near_limit_callback_releases_cache() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let cache: Rc<RefCell<Vec<Gc<NamedObject>>>> = Rc::new(RefCell::new(Vec::new()));
+  for name in &["a", "b", "c"] {
+    cache.borrow_mut().push(scope.alloc(NamedObject { name: String::from(*name) }).unwrap());
+  }
+  let size: usize = scope.allocated_bytes() / 3;
+  scope.set_byte_limit(Some(3 * size));
+  {
+    let cache = Rc::clone(&cache);
+    scope.set_near_limit_callback(1.0, move |_, _| {
+      cache.borrow_mut().clear();
+      GcNearLimitAction::Collect
+    });
+  }
+  // Exceeds the limit: the cache is released and collected
+  let d = scope.alloc(NamedObject { name: String::from("d") }).unwrap();
+  assert!(cache.borrow().is_empty());
+  assert_eq!(d.name, "d");
+  assert_eq!(scope.allocated_bytes(), size);
+}
+
+#[test]
+fn test_gc_external_memory() {
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig { threshold: Some(1 << 20), ..GcConfig::default() });
+  scope.alloc(DropCounter { drops: &drops }).unwrap();
+  // External memory counts towards the collection threshold
+  scope.report_external_alloc(1 << 20);
+  assert_eq!(drops.get(), 1);
+  scope.report_external_free(1 << 20);
+
+  // External memory counts towards the byte limit, and can be freed during a collection
+  scope.set_byte_limit(Some(1 << 20));
+  scope.alloc(ExternalBuffer { scope: &scope, bytes: 1 << 20 }).unwrap();
+  scope.report_external_alloc(1 << 20);
+  assert_eq!(scope.external_bytes(), 1 << 20);
+  scope.alloc(NamedObject { name: String::from("a") }).unwrap();
+  assert_eq!(scope.external_bytes(), 0);
+}
+
+#[test]
+fn test_gc_heap_size() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let empty: Gc<GcRefCell<Vec<u8>>> = scope.alloc(GcRefCell::new(Vec::new())).unwrap();
+  let box_size: usize = scope.allocated_bytes();
+  // The heap memory owned by a value is counted when it is allocated
+  let buffer: Gc<GcRefCell<Vec<u8>>> = scope.alloc(GcRefCell::new(Vec::with_capacity(1000))).unwrap();
+  assert_eq!(scope.allocated_bytes(), 2 * box_size + 1000);
+  // The values managed by other boxes are not counted twice
+  scope.alloc(Some(Gc::clone(&buffer))).unwrap();
+  assert_eq!(scope.allocated_bytes(), 2 * box_size + 1000 + ::std::mem::size_of::<::gc_box::GcBox<Option<Gc<()>>>>());
+  ::std::mem::drop(empty);
+  scope.collect_garbage();
+  assert_eq!(scope.allocated_bytes(), box_size + 1000);
+
+  // The memory acquired by a value is counted once it survives a collection
+  buffer.borrow_mut().reserve_exact(3000);
+  assert_eq!(scope.allocated_bytes(), box_size + 1000);
+  scope.collect_garbage();
+  assert_eq!(scope.allocated_bytes(), box_size + buffer.borrow().capacity());
+  assert!(buffer.borrow().capacity() >= 3000);
+}
+
+#[test]
+fn test_gc_stats() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  assert_eq!(scope.stats(), GcStats::default());
+  let a: Gc<NamedObject> = scope.alloc(NamedObject { name: String::from("a") }).unwrap();
+  let box_size: usize = scope.allocated_bytes();
+  for _ in 0..3 {
+    scope.alloc(NamedObject { name: String::from("b") }).unwrap();
+  }
+  scope.collect_garbage();
+
+  let stats: GcStats = scope.stats();
+  assert_eq!(stats.live_objects, 1);
+  assert_eq!(stats.live_bytes, box_size);
+  assert_eq!(stats.peak_bytes, 4 * box_size);
+  assert_eq!(stats.total_allocations, 4);
+  assert_eq!(stats.total_allocated_bytes, 4 * box_size);
+  assert_eq!(stats.collections, 1);
+  assert_eq!(stats.freed_objects, 3);
+  assert_eq!(stats.freed_bytes, 3 * box_size);
+  let last_collection: GcCollectionResult = stats.last_collection.unwrap();
+  assert_eq!(last_collection.freed_objects, 3);
+  assert_eq!(stats.mark_duration, last_collection.mark_duration);
+  assert_eq!(stats.sweep_duration, last_collection.sweep_duration);
+
+  ::std::mem::drop(a);
+  scope.collect_garbage();
+  let stats: GcStats = scope.stats();
+  assert_eq!(stats.live_objects, 0);
+  assert_eq!(stats.collections, 2);
+  assert_eq!(stats.freed_objects, 4);
+  assert_eq!(stats.last_collection.unwrap().freed_objects, 1);
+}
+
+#[test]
+fn test_gc_collection_hooks() {
+  let events: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let log = Rc::clone(&events);
+  scope.set_before_mark_hook(move |stats| log.borrow_mut().push(format!("before_mark {}", stats.live_objects)));
+  let log = Rc::clone(&events);
+  scope.set_after_mark_hook(move |_| log.borrow_mut().push(String::from("after_mark")));
+  let log = Rc::clone(&events);
+  scope.set_before_sweep_hook(move |_| log.borrow_mut().push(String::from("before_sweep")));
+  let log = Rc::clone(&events);
+  scope.set_after_collection_hook(move |stats| log.borrow_mut().push(format!("after_collection {}", stats.collections)));
+  let log = Rc::clone(&events);
+  scope.set_free_hook(move |type_name, size| log.borrow_mut().push(format!("free {} {}", type_name, size)));
+
+  let _a: Gc<NamedObject> = scope.alloc(NamedObject { name: String::from("a") }).unwrap();
+  scope.alloc(NamedObject { name: String::from("b") }).unwrap();
+  let box_size: usize = scope.allocated_bytes() / 2;
+  scope.collect_garbage();
+  let expected: Vec<String> = vec![
+    String::from("before_mark 2"),
+    String::from("after_mark"),
+    String::from("before_sweep"),
+    format!("free scoped_gc::test::NamedObject {}", box_size),
+    String::from("after_collection 1"),
+  ];
+  assert_eq!(*events.borrow(), expected);
+}
+
+#[test]
+fn test_gc_alloc_from_collection_hook() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let scope_ref: &GcScope = &scope;
+  scope.set_after_mark_hook(move |_| {
+    scope_ref.alloc(NamedObject { name: String::from("a") }).unwrap();
+  });
+  let a: Gc<NamedObject> = scope.alloc(NamedObject { name: String::from("b") }).unwrap();
+  let result = panic::catch_unwind(AssertUnwindSafe(|| scope.collect_garbage()));
+  assert!(result.is_err());
+  // The scope is unlocked, and the hooks can own `Gc` pointers
+  let b: Gc<NamedObject> = Gc::clone(&a);
+  scope.set_after_mark_hook(move |_| assert_eq!(b.name, "b"));
+  scope.alloc(NamedObject { name: String::from("c") }).unwrap();
+  scope.collect_garbage();
+  assert_eq!(a.name, "b");
+  assert_eq!(scope.stats().live_objects, 1);
+}
+
+#[test]
+fn test_gc_type_histogram() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let names: Vec<Gc<NamedObject>> = (0..3).map(|_| scope.alloc(NamedObject { name: String::from("a") }).unwrap()).collect();
+  scope.alloc(NamedObject { name: String::from("b") }).unwrap();
+  scope.alloc(String::with_capacity(1000)).unwrap();
+  scope.collect_garbage();
+
+  let box_size: usize = ::std::mem::size_of::<::gc_box::GcBox<NamedObject>>();
+  let string_size: usize = ::std::mem::size_of::<::gc_box::GcBox<String>>() + 1000;
+  let expected: Vec<GcTypeStats> = vec![
+    GcTypeStats { type_name: "scoped_gc::test::NamedObject", live_objects: 3, live_bytes: 3 * box_size, total_allocations: 4 },
+    GcTypeStats { type_name: "alloc::string::String", live_objects: 0, live_bytes: 0, total_allocations: 1 },
+  ];
+  assert_eq!(scope.type_histogram(), expected);
+  assert_eq!(scope.stats().total_allocated_bytes, 4 * box_size + string_size);
+  ::std::mem::drop(names);
+}
+
+#[test]
+#[cfg(feature = "debug")]
+fn test_gc_allocation_site() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let line: u32 = line!() + 1;
+  let a: Gc<NamedObject> = scope.alloc(NamedObject { name: String::from("a") }).unwrap();
+  assert_eq!(Gc::allocation_site(&a).file(), file!());
+  assert_eq!(Gc::allocation_site(&a).line(), line);
+  for _ in 0..2 {
+    scope.alloc(NamedObject { name: String::from("b") }).unwrap();
+  }
+  scope.collect_garbage();
+
+  let histogram: Vec<::GcSiteStats> = scope.site_histogram();
+  assert_eq!(histogram.len(), 1);
+  assert_eq!(histogram[0].location, Gc::allocation_site(&a));
+  assert_eq!(histogram[0].live_objects, 1);
+  let mut sites: Vec<u32> = Vec::new();
+  scope.for_each_live_with_site(|_, location| sites.push(location.line()));
+  assert_eq!(sites, vec![line]);
+}
+
+#[test]
+#[cfg(feature = "heap-profiler")]
+fn test_gc_heap_profile() {
+  fn contains(profile: &[u8], string: &str) -> bool {
+    profile.windows(string.len()).any(|window| window == string.as_bytes())
+  }
+
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  scope.set_heap_sampling_interval(Some(1));
+  let a: Gc<NamedObject> = scope.alloc(NamedObject { name: String::from("a") }).unwrap();
+  scope.alloc(RefNamedObject { name: "b" }).unwrap();
+  scope.collect_garbage();
+  let profile: Vec<u8> = scope.heap_profile();
+  assert!(contains(&profile, "inuse_space"));
+  assert!(contains(&profile, "scoped_gc::test::NamedObject"));
+  assert!(contains(&profile, "test_gc_heap_profile"));
+  // The samples of the freed values are removed
+  assert!(!contains(&profile, "scoped_gc::test::RefNamedObject"));
+  ::std::mem::drop(a);
+}
+
+/// Records the names of the spans entered on the current thread.
+#[cfg(feature = "tracing")]
+#[derive(Debug, Default)]
+pub struct SpanRecorder {
+  names: ::std::sync::Mutex<Vec<&'static str>>,
+  pub entered: ::std::sync::Arc<::std::sync::Mutex<Vec<&'static str>>>,
+}
+
+#[cfg(feature = "tracing")]
+impl ::tracing::Subscriber for SpanRecorder {
+  fn enabled(&self, _metadata: &::tracing::Metadata<'_>) -> bool {
+    true
+  }
+
+  fn new_span(&self, span: &::tracing::span::Attributes<'_>) -> ::tracing::span::Id {
+    let mut names = self.names.lock().unwrap();
+    names.push(span.metadata().name());
+    ::tracing::span::Id::from_u64(names.len() as u64)
+  }
+
+  fn record(&self, _span: &::tracing::span::Id, _values: &::tracing::span::Record<'_>) {}
+
+  fn record_follows_from(&self, _span: &::tracing::span::Id, _follows: &::tracing::span::Id) {}
+
+  fn event(&self, _event: &::tracing::Event<'_>) {}
+
+  fn enter(&self, span: &::tracing::span::Id) {
+    let name: &'static str = self.names.lock().unwrap()[span.into_u64() as usize - 1];
+    self.entered.lock().unwrap().push(name);
+  }
+
+  fn exit(&self, _span: &::tracing::span::Id) {}
+}
+
+#[test]
+#[cfg(feature = "tracing")]
+fn test_gc_phase_spans() {
+  fn collect_spans<F: FnOnce()>(f: F) -> Vec<&'static str> {
+    let recorder: SpanRecorder = SpanRecorder::default();
+    let entered = ::std::sync::Arc::clone(&recorder.entered);
+    ::tracing::subscriber::with_default(recorder, f);
+    let entered: Vec<&'static str> = entered.lock().unwrap().clone();
+    entered
+  }
+
+  let drops: Cell<usize> = Cell::new(0);
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let _kept = scope.alloc(DropCounter { drops: &drops }).unwrap();
+  scope.alloc(DropCounter { drops: &drops }).unwrap();
+  let full: Vec<&'static str> = collect_spans(|| scope.collect_garbage());
+  assert_eq!(full, vec!["gc_mark", "gc_sweep", "gc_drop"]);
+  assert_eq!(drops.get(), 1);
+
+  // Incremental collections enter the span of each phase once per step
+  scope.alloc(DropCounter { drops: &drops }).unwrap();
+  let incremental: Vec<&'static str> = collect_spans(|| while !scope.collect_step(1) {});
+  assert!(incremental.len() > 3);
+  let mut phases: Vec<&'static str> = incremental.clone();
+  phases.dedup();
+  assert_eq!(phases, vec!["gc_mark", "gc_sweep", "gc_drop"]);
+  assert_eq!(drops.get(), 2);
+}
+
+#[test]
+fn test_gc_heap_walking() {
+  fn count_live(scope: &GcScope) -> usize {
+    let mut live: usize = 0;
+    scope.for_each_live(|_| live += 1);
+    live
+  }
+
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let a: Gc<GcRefCell<NamedObject>> = scope.alloc(GcRefCell::new(NamedObject { name: String::from("a") })).unwrap();
+  let b: Gc<NamedObject> = scope.alloc(NamedObject { name: String::from("b") }).unwrap();
+  scope.alloc(GcRefCell::new(NamedObject { name: String::from("c") })).unwrap();
+  scope.collect_garbage();
+  assert_eq!(count_live(&scope), 2);
+
+  // The values found unreachable by an incremental collection are skipped
+  ::std::mem::drop(b);
+  scope.alloc(GcRefCell::new(NamedObject { name: String::from("d") })).unwrap();
+  assert_eq!(count_live(&scope), 3);
+  while count_live(&scope) != 1 {
+    assert_eq!(count_live(&scope), 3);
+    assert!(!scope.collect_step(1));
+  }
+  assert_eq!(scope.stats().collections, 1);
+  let cells: Vec<Gc<GcRefCell<NamedObject>>> = unsafe { scope.iter_of::<GcRefCell<NamedObject>>() }.collect();
+  assert_eq!(cells.len(), 1);
+  cells[0].borrow_mut().name.push('!');
+  ::std::mem::drop(cells);
+  scope.collect_garbage();
+  assert_eq!(a.borrow().name, "a!");
+  assert_eq!(scope.stats().live_objects, 1);
+}
+
+#[test]
+fn test_gc_iter_of_invariant() {
+  let first: String = String::from("first");
+  let second: String = String::from("second");
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  // All the values of the type use the lifetime of `first`, as required by `iter_of`
+  let a: Gc<InvariantObject> = scope.alloc(InvariantObject { name: Cell::new(&first) }).unwrap();
+  let _b: Gc<InvariantObject> = scope.alloc(InvariantObject { name: Cell::new(&first) }).unwrap();
+  let _c: Gc<RefNamedObject> = scope.alloc(RefNamedObject { name: &first }).unwrap();
+  let objects: Vec<Gc<InvariantObject>> = unsafe { scope.iter_of::<InvariantObject>() }.collect();
+  assert_eq!(objects.len(), 2);
+  for object in objects.iter() {
+    object.name.set(&second);
+  }
+  assert_eq!(a.name.get(), "second");
+}
+
+#[test]
+fn test_gc_long_list() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let mut head = scope.alloc(GcRefCell::new(ListNode { next: None })).unwrap();
+  let tail = Gc::clone(&head);
+  for _ in 0..1_000_000 {
+    head = scope.alloc(GcRefCell::new(ListNode { next: Some(head) })).unwrap();
+  }
+  // Marking a million-element list must not overflow the native stack
+  scope.collect_garbage();
+  let last = scope.alloc(GcRefCell::new(ListNode { next: None })).unwrap();
+  tail.borrow_mut().next = Some(Gc::clone(&last));
+  ::std::mem::drop(head);
+  scope.collect_garbage();
+  assert!(tail.borrow().next.is_some());
+}
@@ -1,55 +1,51 @@
+use finalize::Finalize;
+use heap_size::HeapSize;
+
 /// Used to propagate signals across the objects graph of values managed by the garbage collector.
 ///
 /// This trait is `unsafe` because an invalid implementations may cause dangling pointers.
-/// For example, if `trace` is not propagated to a reachable `Gc` pointers then its value may be
+/// For example, if `mark` is not propagated to a reachable `Gc` pointers then its value may be
 /// freed. The next dereference of this `Gc` causes then an error.
 ///
 /// You should never initiate the traversal of the object graph: it is the role of the library.
 /// Propagating a signal at the wrong time may cause an invalid state leading to dangling pointers.
-pub unsafe trait Trace {
-  /// Propagates the `mark` signal across the objects graph.
-  ///
-  /// This signal is used during the first phase of garbage collection to mark the values that are
-  /// still reachable.
-  ///
-  /// The signal is initiated at the rooted values and propagated to reach the adjacent `Gc`
-  /// pointers. If their value is not already marked they mark it and propagate the signal further
-  /// in the graph.
-  unsafe fn mark(&self);
-
-  /// Propagates the `root` signal across the objects graph.
+///
+/// # Safety
+///
+/// Implementations must propagate the signal to all the `Trace` values they own.
+pub unsafe trait Trace: Finalize + HeapSize {
+  /// Visits the `Gc` pointers owned by this value.
   ///
-  /// This signal is used to signal that there is a new root pointing to a managed value. This
-  /// prevents this value (and any other value reachable from it) from being collected.
+  /// This is used by the collector in two ways. Before marking, it counts the references between
+  /// managed values: the values referenced by more `Gc` pointers than the managed values pointing
+  /// to them are rooted. It then propagates the `mark` signal from these rooted values to mark the
+  /// values that are still reachable.
   ///
-  /// This is initiated when creating a new `Gc` pointer or mutably borrowing the value inside a
-  /// `GcRefCell`.
-  unsafe fn root(&self);
-
-  /// Propagates the `unroot` signal across the objects graph.
+  /// The signal reaches the adjacent `Gc` pointers. If their value is not already marked they mark
+  /// it and queue it in the worklist of the collector: the signal is propagated further in the
+  /// graph once the collector traces it.
+  /// Implementations only need to visit the values they own, the depth of the graph does not
+  /// affect the depth of the native stack.
   ///
-  /// This signal is used to signal the destruction of a root pointing to a managed value. If this
-  /// was the last root, the value is not longer used to initiate `mark` signals: if there are no
-  /// other rooted values that can reach it, it becomes eligible for garbage collection.
+  /// # Safety
   ///
-  /// This is initiated when a `Gc` pointer or mutably borrowing the value inside a
-  /// `GcRefCell`.
-  unsafe fn unroot(&self);
+  /// This must only be called by the garbage collector.
+  unsafe fn mark(&self);
 }
 
-/// This macro rule implements `Trace` with empty functions.
+/// This macro rule implements `Trace`, `Finalize` and `HeapSize` with empty functions.
 ///
-/// Use this for types that can't contain other `Trace` types.
+/// Use this for types that can't contain other `Trace` types, and don't own heap memory.
 #[macro_export]
 macro_rules! unsafe_empty_trace {
   ($T: ty) => {
+    impl $crate::Finalize for $T {}
+
+    impl $crate::HeapSize for $T {}
+
     unsafe impl Trace for $T {
       #[inline]
       unsafe fn mark(&self) {}
-      #[inline]
-      unsafe fn root(&self) {}
-      #[inline]
-      unsafe fn unroot(&self) {}
     }
   }
 }
@@ -69,13 +65,25 @@ unsafe_empty_trace!(isize);
 unsafe_empty_trace!(f32);
 unsafe_empty_trace!(f64);
 unsafe_empty_trace!(char);
-unsafe_empty_trace!(String);
 unsafe_empty_trace!(::std::path::Path);
-unsafe_empty_trace!(::std::path::PathBuf);
 unsafe_empty_trace!(::std::sync::atomic::AtomicBool);
 unsafe_empty_trace!(::std::sync::atomic::AtomicIsize);
 unsafe_empty_trace!(::std::sync::atomic::AtomicUsize);
 
+impl Finalize for String {}
+
+unsafe impl Trace for String {
+  #[inline]
+  unsafe fn mark(&self) {}
+}
+
+impl Finalize for ::std::path::PathBuf {}
+
+unsafe impl Trace for ::std::path::PathBuf {
+  #[inline]
+  unsafe fn mark(&self) {}
+}
+
 #[macro_export]
 macro_rules! unsafe_custom_trace {
   ($this:ident, $body:expr) => {
@@ -86,23 +94,11 @@ macro_rules! unsafe_custom_trace {
       let $this = self;
       $body
     }
-    #[inline]
-    unsafe fn root(&self) {
-      #[inline]
-      unsafe fn trace<T: $crate::Trace>(it: &T) { $crate::Trace::root(it) }
-      let $this = self;
-      $body
-    }
-    #[inline]
-    unsafe fn unroot(&self) {
-      #[inline]
-      unsafe fn trace<T: $crate::Trace>(it: &T) { $crate::Trace::unroot(it) }
-      let $this = self;
-      $body
-    }
   }
 }
 
+impl<T: Trace> Finalize for ::std::ops::Range<T> {}
+
 unsafe impl<T: Trace> Trace for ::std::ops::Range<T> {
   unsafe_custom_trace!(this, {
     trace(&this.start);
@@ -110,18 +106,24 @@ unsafe impl<T: Trace> Trace for ::std::ops::Range<T> {
   });
 }
 
+impl<T: Trace> Finalize for Box<T> {}
+
 unsafe impl<T: Trace> Trace for Box<T> {
   unsafe_custom_trace!(this, {
     trace(&**this)
   });
 }
 
+impl<T: Trace> Finalize for Option<T> {}
+
 unsafe impl<T: Trace> Trace for Option<T> {
   unsafe_custom_trace!(this, {
     if let Some(ref x) = *this { trace(x) }
   });
 }
 
+impl<T: Trace, E: Trace> Finalize for Result<T, E> {}
+
 unsafe impl<T: Trace, E: Trace> Trace for Result<T, E> {
   unsafe_custom_trace!(this, {
     match *this {
@@ -131,6 +133,8 @@ unsafe impl<T: Trace, E: Trace> Trace for Result<T, E> {
   });
 }
 
+impl<T: Trace> Finalize for Vec<T> {}
+
 unsafe impl<T: Trace> Trace for Vec<T> {
   unsafe_custom_trace!(this, {
     for item in this {
@@ -139,6 +143,8 @@ unsafe impl<T: Trace> Trace for Vec<T> {
   });
 }
 
+impl<K: Eq + ::std::hash::Hash + Trace, V: Trace> Finalize for ::std::collections::HashMap<K, V> {}
+
 unsafe impl<K: Eq + ::std::hash::Hash + Trace, V: Trace> Trace for ::std::collections::HashMap<K, V> {
   unsafe_custom_trace!(this, {
     for (k, v) in this.iter() {
@@ -148,6 +154,8 @@ unsafe impl<K: Eq + ::std::hash::Hash + Trace, V: Trace> Trace for ::std::collec
   });
 }
 
+impl<K: Eq + ::std::cmp::Ord + Trace, V: Trace> Finalize for ::std::collections::BTreeMap<K, V> {}
+
 unsafe impl<K: Eq + ::std::cmp::Ord + Trace, V: Trace> Trace for ::std::collections::BTreeMap<K, V> {
   unsafe_custom_trace!(this, {
     for (k, v) in this.iter() {
@@ -157,11 +165,9 @@ unsafe impl<K: Eq + ::std::cmp::Ord + Trace, V: Trace> Trace for ::std::collecti
   });
 }
 
-unsafe impl<'a> Trace for &'a str {
+impl Finalize for &str {}
+
+unsafe impl Trace for &str {
   #[inline]
   unsafe fn mark(&self) {}
-  #[inline]
-  unsafe fn root(&self) {}
-  #[inline]
-  unsafe fn unroot(&self) {}
 }
@@ -0,0 +1,65 @@
+//! Checks the `Trace`, `Finalize` and `HeapSize` derives of `scoped-gc-derive` against this crate.
+
+// The derives implement the traits inside a constant, to import the crate
+#![allow(non_local_definitions)]
+
+extern crate scoped_gc;
+#[macro_use]
+extern crate scoped_gc_derive;
+
+use scoped_gc::{Finalize, Gc, GcConfig, GcScope, HeapSize, Trace};
+
+#[derive(Trace, Finalize, HeapSize)]
+struct NamedNode<'gc> {
+  name: String,
+  parent: Option<Gc<'gc, NamedNode<'gc>>>,
+}
+
+#[derive(Trace, Finalize, HeapSize)]
+enum Value<'gc, T: Trace + 'gc> {
+  Empty,
+  Owned(T),
+  Shared(Gc<'gc, NamedNode<'gc>>),
+}
+
+#[test]
+fn test_derive_struct() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let root: Gc<NamedNode> = scope.alloc(NamedNode { name: String::with_capacity(16), parent: None }).unwrap();
+  let child: Gc<NamedNode> = {
+    let parent: Gc<NamedNode> = scope.alloc(NamedNode { name: String::from("parent"), parent: Some(root) }).unwrap();
+    scope.alloc(NamedNode { name: String::with_capacity(32), parent: Some(parent) }).unwrap()
+  };
+  // The `Gc` fields are not counted: their values are measured separately
+  let grandparent: &NamedNode = child.parent.as_ref().unwrap().parent.as_ref().unwrap();
+  assert_eq!((*child).heap_size(), 32);
+  assert_eq!(grandparent.heap_size(), 16);
+  (*child).finalize();
+  // The parents are only reachable through the `Gc` fields traced by the derive
+  scope.collect_garbage();
+  assert_eq!(scope.stats().live_objects, 3);
+  drop(child);
+  scope.collect_garbage();
+  assert_eq!(scope.stats().live_objects, 0);
+}
+
+#[test]
+fn test_derive_generic_enum() {
+  let scope: GcScope = GcScope::with_config(GcConfig::manual());
+  let node: Gc<NamedNode> = scope.alloc(NamedNode { name: String::with_capacity(8), parent: None }).unwrap();
+  let values: Vec<Value<Vec<u64>>> = vec![Value::Empty, Value::Owned(Vec::with_capacity(4)), Value::Shared(node)];
+  assert_eq!(values[0].heap_size(), 0);
+  assert_eq!(values[1].heap_size(), 32);
+  assert_eq!(values[2].heap_size(), 0);
+  values[2].finalize();
+  let shared: Gc<Value<String>> = {
+    let node: Gc<NamedNode> = scope.alloc(NamedNode { name: String::from("shared"), parent: None }).unwrap();
+    scope.alloc(Value::Shared(node)).unwrap()
+  };
+  drop(values);
+  scope.collect_garbage();
+  assert_eq!(scope.stats().live_objects, 2);
+  drop(shared);
+  scope.collect_garbage();
+  assert_eq!(scope.stats().live_objects, 0);
+}